@@ -2,212 +2,1131 @@
 // =========================================
 
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 use std::ops::Deref;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::sync::Arc;
-use std::{fs, io, thread};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io, mem, thread};
 
 extern crate regex;
 
-use self::regex::Regex;
-use part14::sort;
+use self::external_sort::ExternalSorter;
+use self::regex::bytes::{Regex, RegexBuilder};
+use self::search::{FileStats, Found, FoundLine, Matcher};
+use self::stats::Stats;
+use std::borrow::Cow;
+
+mod decompress;
+mod discover;
+mod external_sort;
+mod in_place;
+mod json;
+mod search;
+mod searcher;
+mod stats;
+mod watch;
+
+pub use self::discover::{discover_files, FileFilter};
+pub use self::searcher::{Printer, Searcher, Sink};
 
 // Before we come to the actual code, we define a data-structure `Options` to store all the
 // information we need to complete the job: Which files to work on, which pattern to look for, and
-// how to output.
-#[derive(Clone, Copy)]
+// how to output. `SortAndPrint` says what to sort by. `Replace` carries the template that matches
+// are substituted with; it may refer to capture groups as `$1` or `${name}`. `ReplaceInPlace`
+// writes the result back to the files instead of printing it, or just shows the diff in `dry_run`
+// mode. `Json` prints machine-readable events. `FilesWithMatches` and `FilesWithoutMatch` only list
+// file names, and `CountPerFile` prints the number of matching lines of every file that has any.
+// `Quiet` prints nothing and stops at the first match; `run` tells whether there was one. `Watch`
+// keeps printing the matches in the lines appended to the files.
+#[derive(Clone)]
 pub enum OutputMode {
     Print,
-    SortAndPrint,
+    SortAndPrint(SortOrder),
     Count,
+    CountPerFile,
+    FilesWithMatches,
+    FilesWithoutMatch,
+    Json,
+    OnlyMatching,
+    Replace(String),
+    ReplaceInPlace { template: String, dry_run: bool },
+    Quiet,
+    Watch,
 }
 use self::OutputMode::*;
 
+// Whether the output should be colored is decided by the user, or, in `Auto` mode, by checking
+// whether stdout is a terminal: escape sequences are only useful when a human is looking.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn should_color(self) -> bool {
+        match self {
+            ColorChoice::Auto => io::stdout().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+// Sorted output is ordered by the file `Path`, the `Line` number or the `Content` of the lines, or
+// in the `reverse` order of one of these. Lines that are the same in this respect stay in the order
+// of their files and line numbers, so the result is always the same.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    Path,
+    Line,
+    Content,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub reverse: bool,
+}
+
+impl SortOrder {
+    fn compare(self, a: &MatchedLine, b: &MatchedLine) -> Ordering {
+        let by_key = match self.key {
+            SortKey::Path => a.file_name.cmp(&b.file_name),
+            SortKey::Line => a.number.cmp(&b.number),
+            SortKey::Content => a.line.cmp(&b.line),
+        };
+        let by_key = if self.reverse {
+            by_key.reverse()
+        } else {
+            by_key
+        };
+
+        by_key
+            .then_with(|| a.file_name.cmp(&b.file_name))
+            .then_with(|| a.number.cmp(&b.number))
+    }
+}
+
+// Binary files are `Detect`ed and reported as a whole by default. They can also be searched like
+// text, or skipped altogether.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryMode {
+    Detect,
+    Text,
+    Skip,
+}
+
+// What is printed in front of a matching line: the file name, the number of the line, the column
+// of the first match in it and the byte offset of the line in the file. Lines and columns are
+// counted from 1, like editors do, while offsets start at 0. In `vimgrep` format, every match gets
+// a line of its own, with the file name, line number and column in front, just like `vim` expects
+// them in its quickfix list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineFormat {
+    pub file_name: bool,
+    pub line_number: bool,
+    pub column: bool,
+    pub byte_offset: bool,
+    pub vimgrep: bool,
+}
+
+impl Default for LineFormat {
+    fn default() -> Self {
+        LineFormat {
+            file_name: true,
+            line_number: true,
+            column: false,
+            byte_offset: false,
+            vimgrep: false,
+        }
+    }
+}
+
+// With `ignore_case`, upper and lower case letters match each other, in literal mode as well as in
+// regular-expression mode. `threads` is the number of worker threads searching files in parallel,
+// and `mmap` allows them to memory-map large files rather than reading them. With `search_zip`,
+// compressed files are decompressed while searching them. In `multiline` mode, matches may span
+// several lines. At most `max_count` matching lines are reported per file, and at most
+// `max_total` altogether. Sorting keeps up to about `sort_memory` bytes of lines in memory, and the
+// rest in temporary files. With `stats`, some statistics about the search are printed after the
+// output. `line_format` says what goes in front of the printed lines.
 pub struct Options {
     pub use_regexp_mode: bool,
+    pub ignore_case: bool,
+    pub threads: usize,
+    pub mmap: bool,
+    pub search_zip: bool,
+    pub multiline: bool,
+    pub max_count: Option<usize>,
+    pub max_total: Option<usize>,
+    pub sort_memory: usize,
+    pub stats: bool,
+    pub binary: BinaryMode,
     pub files: Vec<Arc<String>>,
-    pub pattern: String,
+    pub patterns: Vec<String>,
     pub output_mode: OutputMode,
+    pub line_format: LineFormat,
+    pub color: ColorChoice,
 }
 
+impl Options {
+    // The options of a plain search for `patterns`, to be adapted as needed: the patterns are
+    // literal, the lines are printed without color, binary files are only reported as a whole, and
+    // there are as many threads as the machine has cores. There are no files yet.
+    pub fn new<S: Into<String>>(patterns: impl IntoIterator<Item = S>) -> Self {
+        Options {
+            use_regexp_mode: false,
+            ignore_case: false,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            mmap: false,
+            search_zip: false,
+            multiline: false,
+            max_count: None,
+            max_total: None,
+            sort_memory: 256 * 1024 * 1024,
+            stats: false,
+            binary: BinaryMode::Detect,
+            files: Vec::new(),
+            patterns: patterns.into_iter().map(Into::into).collect(),
+            output_mode: Print,
+            line_format: LineFormat::default(),
+            color: ColorChoice::Never,
+        }
+    }
+}
+
+// Besides the line itself, we remember the byte ranges of all matches in it, so that the output
+// can point at them. The line is kept as raw bytes, as files need not be valid UTF-8. Its `number`
+// counts from 1, and `offset` is where it starts in the file. For a `binary` file, the line is
+// usually the only one we send, standing in for the whole file. Files are told apart by their
+// `file_index` among the searched ones, as the same name may be given twice.
 #[derive(Debug)]
-struct MatchedLine {
-    file_name: Arc<String>,
-    number: usize,
-    line: String,
+pub struct MatchedLine {
+    pub file_name: Arc<String>,
+    pub file_index: usize,
+    pub number: usize,
+    pub offset: usize,
+    pub line: Vec<u8>,
+    pub matches: Vec<(usize, usize)>,
+    pub binary: bool,
 }
 
+// Matched lines are naturally ordered by their content, and lines with the same content by file
+// and line number. Equality agrees with that order, so lines from different files are never equal.
+const BY_CONTENT: SortOrder = SortOrder {
+    key: SortKey::Content,
+    reverse: false,
+};
+
 impl PartialEq<Self> for MatchedLine {
     fn eq(&self, other: &Self) -> bool {
-        self.line == other.line
+        BY_CONTENT.compare(self, other) == Ordering::Equal
     }
 }
 
 impl PartialOrd for MatchedLine {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.line.partial_cmp(&other.line)
+        Some(BY_CONTENT.compare(self, other))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use part13::MatchedLine;
-    use part14::sort;
+// `sort` needs the order in the type, so for the other orders we wrap the lines.
+struct SortedLine {
+    order: SortOrder,
+    matched_line: MatchedLine,
+}
 
-    #[test]
-    fn test_matched_line_partial_ord() {
-        let mut lines = vec![
-            MatchedLine {
-                file_name: "file1".to_string().into(),
-                line: "b".to_string(),
-                number: 1,
-            },
-            MatchedLine {
-                file_name: "file2".to_string().into(),
-                line: "a".to_string(),
-                number: 2,
-            },
-        ];
+impl PartialEq<Self> for SortedLine {
+    fn eq(&self, other: &Self) -> bool {
+        self.order.compare(&self.matched_line, &other.matched_line) == Ordering::Equal
+    }
+}
 
-        sort(&mut lines);
+impl PartialOrd for SortedLine {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.order.compare(&self.matched_line, &other.matched_line))
+    }
+}
 
-        let expected = vec![
-            MatchedLine {
-                file_name: "file2".to_string().into(),
-                line: "a".to_string(),
-                number: 2,
-            },
-            MatchedLine {
-                file_name: "file1".to_string().into(),
-                line: "b".to_string(),
-                number: 1,
-            },
-        ];
+// Sending every single line over a channel is expensive, so the matching lines travel in batches
+// of up to `BATCH_SIZE`. A `FileBatch` is tagged with the index of the file it belongs to; the
+// `last` batch of a file tells the collector that the file is done, and carries the `error` that
+// kept us from searching all of it, if any.
+const BATCH_SIZE: usize = 1024;
 
-        assert_eq!(lines, expected);
+struct FileBatch {
+    index: usize,
+    lines: Vec<MatchedLine>,
+    last: bool,
+    error: Option<io::Error>,
+}
+
+// Once the output thread is done with a batch, it sends the empty vector back, so that the workers
+// can fill it again instead of allocating a new one.
+type Recycled = Arc<Mutex<Receiver<Vec<MatchedLine>>>>;
+
+// The worker side of a batch: lines are pushed one by one, and the batch is sent whenever it is
+// full.
+struct BatchSender<'a> {
+    index: usize,
+    lines: Vec<MatchedLine>,
+    out_channel: &'a SyncSender<FileBatch>,
+    recycled: &'a Recycled,
+}
+
+impl<'a> BatchSender<'a> {
+    fn new(index: usize, out_channel: &'a SyncSender<FileBatch>, recycled: &'a Recycled) -> Self {
+        let mut batch_sender = BatchSender {
+            index,
+            lines: Vec::new(),
+            out_channel,
+            recycled,
+        };
+        batch_sender.lines = batch_sender.fresh_batch();
+        batch_sender
+    }
+
+    fn fresh_batch(&self) -> Vec<MatchedLine> {
+        let recycled = self.recycled.lock().unwrap().try_recv();
+
+        recycled.unwrap_or_else(|_| Vec::with_capacity(BATCH_SIZE))
+    }
+
+    fn send(&mut self, last: bool, error: Option<io::Error>) {
+        let next_lines = if last { Vec::new() } else { self.fresh_batch() };
+        let lines = mem::replace(&mut self.lines, next_lines);
+        let batch = FileBatch {
+            index: self.index,
+            lines,
+            last,
+            error,
+        };
+
+        // Sending only fails if the output is done early, say because `max_total` lines were
+        // printed. The worker will notice that the search was cancelled, so we can drop the lines.
+        let _ = self.out_channel.send(batch);
+    }
+
+    fn push(&mut self, matched_line: MatchedLine) {
+        self.lines.push(matched_line);
+
+        if self.lines.len() == BATCH_SIZE {
+            self.send(false, None);
+        }
+    }
+
+    // Sends what we have so far, if anything.
+    fn flush(&mut self) {
+        if !self.lines.is_empty() {
+            self.send(false, None);
+        }
+    }
+
+    fn finish(mut self, error: Option<io::Error>) {
+        self.send(true, error);
     }
 }
 
-// The first function reads the files, and sends every line over the `out_channel`.
-fn read_files(options: Arc<Options>, out_channel: SyncSender<MatchedLine>) {
-    for file_name in options.files.iter() {
-        // First, we open the file, ignoring any errors.
-        let file = fs::File::open(file_name.deref()).unwrap();
-        // Then we obtain a `BufReader` for it, which provides the `lines` function.
-        let file = io::BufReader::new(file);
+// Matches in what we read from standard input (given as "-") are labeled like this.
+const STDIN_LABEL: &str = "(standard input)";
 
-        for (line_number, line) in file.lines().enumerate() {
-            let line = line.unwrap();
-            let matched_line = MatchedLine {
-                file_name: file_name.clone(),
-                number: line_number,
-                line,
-            };
+// How far the search got, shared by the workers and the collector: the index of the next file to
+// hand out, and the number of matching lines in the files the collector is done with, which come
+// before all files still being searched. The collector has to keep the batches of the files after
+// the one it is `in_order` at, so the workers wait for it if they get too far ahead.
+#[derive(Default)]
+struct Progress {
+    next_file: AtomicUsize,
+    lines_done: AtomicUsize,
+    in_order: Mutex<usize>,
+    moved_on: Condvar,
+}
+
+impl Progress {
+    // Blocks until the file at `index` is less than `window` files ahead of the collector, or the
+    // search is cancelled.
+    fn wait_for(&self, index: usize, window: usize, cancelled: &AtomicBool) {
+        let mut in_order = self.in_order.lock().unwrap();
+
+        while index >= in_order.saturating_add(window) && !cancelled.load(atomic::Ordering::SeqCst)
+        {
+            in_order = self.moved_on.wait(in_order).unwrap();
+        }
+    }
+
+    fn move_on(&self, index: usize) {
+        *self.in_order.lock().unwrap() = index;
+        self.moved_on.notify_all();
+    }
+}
+
+// How many files per worker may be ahead of the collector.
+const FILES_AHEAD: usize = 4;
 
-            // Now we send the line over the channel, ignoring the possibility of `send` failing.
-            out_channel.send(matched_line).unwrap();
+// A worker searching a single file pushes the matching lines into batches, and counts them. It
+// decides when the file is done: listing files needs a single match, and `Quiet` needs a single one
+// for the whole search, so it cancels the others. Lines beyond `max_count` are not needed, and
+// neither are those beyond `max_total`, counting the lines of the files before this one that are
+// done. Once the search is `cancelled`, the rest of the file is not needed either.
+struct FileSearch<'a> {
+    options: &'a Options,
+    file_name: &'a Arc<String>,
+    batch_sender: BatchSender<'a>,
+    cancelled: &'a AtomicBool,
+    progress: &'a Progress,
+    matched: usize,
+    match_count: usize,
+}
+
+impl<'a> Found for FileSearch<'a> {
+    fn found(&mut self, found_line: FoundLine) -> bool {
+        let options = self.options;
+
+        // With a `max_count` of 0, even the first line is one too many.
+        if options
+            .max_count
+            .is_some_and(|max_count| self.matched >= max_count)
+        {
+            return false;
         }
+
+        self.matched += 1;
+        self.match_count += found_line.matches.len();
+        if !matches!(options.output_mode, FilesWithoutMatch) {
+            self.batch_sender.push(MatchedLine {
+                file_name: self.file_name.clone(),
+                file_index: self.batch_sender.index,
+                number: found_line.number,
+                offset: found_line.offset,
+                line: found_line.line.to_vec(),
+                matches: found_line.matches,
+                binary: found_line.binary,
+            });
+        }
+
+        if let Quiet = options.output_mode {
+            self.cancelled.store(true, atomic::Ordering::SeqCst);
+        }
+        let total = self.progress.lines_done.load(atomic::Ordering::SeqCst) + self.matched;
+
+        !matches!(
+            options.output_mode,
+            FilesWithMatches | FilesWithoutMatch | Quiet
+        ) && options
+            .max_count
+            .is_none_or(|max_count| self.matched < max_count)
+            && options.max_total.is_none_or(|max_total| total < max_total)
+            && self.keep_reading()
+    }
+
+    fn keep_reading(&mut self) -> bool {
+        !self.cancelled.load(atomic::Ordering::SeqCst)
+    }
+
+    fn flush(&mut self) {
+        self.batch_sender.flush();
     }
-    // When we drop the `out_channel`, it will be closed, which the other end can notice.
 }
 
-// The second function filters the lines it receives through `in_channel` with the pattern, and sends
-// matches via `out_channel`.
-fn filter_lines<'a>(
+// The first function is run by every worker thread. The workers take the files one after the other
+// from `progress`, so each file is taken by exactly one worker, and wait before searching a file
+// too far ahead of the collector. The matches of a file are sent over `out_channel` in batches
+// tagged with the index of the file, as the workers finish their files in no particular order. As
+// soon as a file is done, its last batch goes out, even if it is not full, and so does the current
+// one whenever reading the file stalls. Files without a match are represented by a line without
+// content when listing them, as otherwise there would be nothing to list. Once the output needs no
+// more lines, `cancelled` is set, and the workers stop reading and do not start any more files.
+// What the workers read and find is added up in `stats`. The patterns are compiled only once, into
+// the `matcher` all workers share.
+fn search_files(
     options: Arc<Options>,
-    in_channel: Receiver<MatchedLine>,
-    out_channel: SyncSender<MatchedLine>,
+    matcher: Arc<Matcher>,
+    progress: Arc<Progress>,
+    cancelled: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    out_channel: SyncSender<FileBatch>,
+    recycled: Recycled,
 ) {
-    let re = Regex::new(&options.pattern).unwrap();
+    let stdin_label = Arc::new(STDIN_LABEL.to_string());
+    // Every worker reads its files into the same buffer, over and over again.
+    let mut buffer = Vec::new();
 
-    // We can simply iterate over the channel, which will stop when the channel is closed.
-    for matched_line in in_channel.iter() {
-        let matched = if options.use_regexp_mode {
-            re.is_match(&matched_line.line)
+    while !cancelled.load(atomic::Ordering::SeqCst) {
+        let index = progress.next_file.fetch_add(1, atomic::Ordering::SeqCst);
+        let Some(file_name) = options.files.get(index) else {
+            break;
+        };
+        progress.wait_for(index, options.threads.max(1) * FILES_AHEAD, &cancelled);
+        let display_name = if file_name.as_str() == "-" {
+            &stdin_label
         } else {
-            // `contains` works on lots of types of patterns, but in particular, we can use it to test
-            // whether one string is contained in another. This is another example of Rust using traits
-            // as substitute for overloading.
-            matched_line.line.contains(&options.pattern)
+            file_name
         };
+        let started = Instant::now();
+        let mut file_stats = FileStats::default();
+        let mut file_search = FileSearch {
+            options: &options,
+            file_name: display_name,
+            batch_sender: BatchSender::new(index, &out_channel, &recycled),
+            cancelled: &cancelled,
+            progress: &progress,
+            matched: 0,
+            match_count: 0,
+        };
+
+        // A file we cannot read is reported along with its last batch, but must not stop the
+        // search. The error names the file, as the lines do.
+        let result = search::search_file(
+            &options,
+            &matcher,
+            file_name,
+            &mut buffer,
+            &mut file_stats,
+            &mut file_search,
+        );
+        let FileSearch {
+            mut batch_sender,
+            matched,
+            match_count,
+            ..
+        } = file_search;
+        let error = match result {
+            Err(error) => Some(io::Error::new(
+                error.kind(),
+                format!("{display_name}: {error}"),
+            )),
+            Ok(()) => {
+                if matches!(options.output_mode, FilesWithoutMatch) && matched == 0 {
+                    batch_sender.push(MatchedLine {
+                        file_name: display_name.clone(),
+                        file_index: index,
+                        number: 0,
+                        offset: 0,
+                        line: Vec::new(),
+                        matches: Vec::new(),
+                        binary: false,
+                    });
+                }
+                stats.add_file(&file_stats, matched, match_count, started.elapsed());
+                None
+            }
+        };
+
+        batch_sender.finish(error);
+    }
+    // When the last worker drops its `out_channel`, it will be closed, which the other end can
+    // notice.
+}
+
+// The second function brings the batches of the workers back into the order of the files. Batches
+// of the file we are currently at are passed on right away, the others are kept until all files
+// before them are done. The matching lines of the files that are done are counted in `progress`.
+// The error of a file is passed on after its lines. Whenever a file is done, the workers waiting to
+// search files further ahead are told.
+fn collect_in_order(
+    in_channel: Receiver<FileBatch>,
+    out_channel: SyncSender<io::Result<Vec<MatchedLine>>>,
+    progress: Arc<Progress>,
+) {
+    let mut pending: HashMap<usize, Vec<FileBatch>> = HashMap::new();
+    let mut next_index = 0;
+    // The matching lines of the file at `next_index` we passed on so far.
+    let mut lines = 0;
+
+    // We can simply iterate over the channel, which will stop when the channel is closed.
+    'batches: for batch in in_channel.iter() {
+        pending.entry(batch.index).or_default().push(batch);
+
+        while let Some(batches) = pending.get_mut(&next_index) {
+            let mut finished = false;
+
+            for batch in batches.drain(..) {
+                finished = batch.last;
+                lines += batch.lines.len();
+                // If the output is done early, so are we. Dropping `in_channel` tells the workers.
+                if !batch.lines.is_empty() && out_channel.send(Ok(batch.lines)).is_err() {
+                    break 'batches;
+                }
+                if let Some(error) = batch.error {
+                    if out_channel.send(Err(error)).is_err() {
+                        break 'batches;
+                    }
+                }
+            }
+
+            if !finished {
+                break;
+            }
+            pending.remove(&next_index);
+            next_index += 1;
+            progress
+                .lines_done
+                .fetch_add(mem::take(&mut lines), atomic::Ordering::SeqCst);
+            progress.move_on(next_index);
+        }
+    }
+
+    // Whether we are done or the output is, no worker must wait for us any longer.
+    progress.move_on(usize::MAX);
+}
+
+// On the receiving end, we turn the batches back into a stream of lines, which is what the output
+// modes work with, and the errors about files in between. Emptied batches are sent back to the
+// workers. The stream ends after `remaining` lines, and then cancels the search. How long we wait
+// for batches goes into `stats`.
+struct Batches {
+    in_channel: Receiver<io::Result<Vec<MatchedLine>>>,
+    recycle: SyncSender<Vec<MatchedLine>>,
+    // The current batch, in reverse order so that we can cheaply `pop` the next line.
+    current: Vec<MatchedLine>,
+    remaining: Option<usize>,
+    cancelled: Arc<AtomicBool>,
+    // The number of lines we handed out, and of files we could not search.
+    seen: usize,
+    failed: usize,
+    stats: Arc<Stats>,
+}
+
+impl Iterator for Batches {
+    type Item = io::Result<MatchedLine>;
+
+    fn next(&mut self) -> Option<io::Result<MatchedLine>> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+
+        loop {
+            if let Some(matched_line) = self.current.pop() {
+                self.seen += 1;
+                if let Some(ref mut remaining) = self.remaining {
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        self.cancelled.store(true, atomic::Ordering::SeqCst);
+                    }
+                }
+                return Some(Ok(matched_line));
+            }
+
+            let waiting = Instant::now();
+            let batch = self.in_channel.recv();
+            self.stats.add_wait(waiting.elapsed());
+            let mut batch = match batch.ok()? {
+                Ok(batch) => batch,
+                Err(error) => {
+                    self.failed += 1;
+                    return Some(Err(error));
+                }
+            };
+            batch.reverse();
+            let empty = mem::replace(&mut self.current, batch);
+            // If the workers have enough batches already, we just drop this one.
+            if empty.capacity() > 0 {
+                let _ = self.recycle.try_send(empty);
+            }
+        }
+    }
+}
+
+// These are the ANSI escape sequences we use for highlighting: magenta file names, green line
+// numbers and bold red matches, just like `grep --color` does.
+const COLOR_FILE_NAME: &str = "\x1b[35m";
+const COLOR_NUMBER: &str = "\x1b[32m";
+const COLOR_MATCH: &str = "\x1b[1;31m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+// Wraps `text` in the escape sequences for `color_code` if `color` is set.
+fn paint(text: impl Display, color_code: &str, color: bool) -> String {
+    if color {
+        format!("{color_code}{text}{COLOR_RESET}")
+    } else {
+        text.to_string()
+    }
+}
+
+// Formats a matched line for printing, wrapping the interesting parts in escape sequences if
+// `color` is set. Only here do we decode the line; bytes that are not valid UTF-8 are shown as
+// the replacement character. We decode the pieces between matches separately, so that the byte
+// ranges of the matches stay valid. In front of the line goes what `format` asks for, where the
+// column is that of the match starting at byte `start`, and the line is at `offset` in the file.
+// Binary files are not printed at all, we just say that they match.
+fn format_line_at(
+    matched_line: &MatchedLine,
+    format: LineFormat,
+    start: usize,
+    offset: usize,
+    color: bool,
+) -> String {
+    let MatchedLine {
+        file_name,
+        number,
+        line,
+        matches,
+        binary,
+        ..
+    } = matched_line;
+
+    if *binary {
+        return format!("Binary file {file_name} matches");
+    }
+
+    let mut prefix = Vec::new();
+    if format.file_name || format.vimgrep {
+        prefix.push(paint(file_name, COLOR_FILE_NAME, color));
+    }
+    if format.line_number || format.vimgrep {
+        prefix.push(paint(number, COLOR_NUMBER, color));
+    }
+    if format.column || format.vimgrep {
+        prefix.push(paint(start + 1, COLOR_NUMBER, color));
+    }
+    if format.byte_offset {
+        prefix.push(paint(offset, COLOR_NUMBER, color));
+    }
+
+    // Without colors, there is nothing to highlight, and we decode the line as a whole.
+    let matches: &[(usize, usize)] = if color { matches } else { &[] };
+    let mut highlighted = String::with_capacity(line.len());
+    let mut last_end = 0;
+
+    for &(start, end) in matches {
+        // Empty matches have nothing to highlight.
+        if start == end {
+            continue;
+        }
+
+        highlighted.push_str(&String::from_utf8_lossy(&line[last_end..start]));
+        highlighted.push_str(&paint(
+            String::from_utf8_lossy(&line[start..end]),
+            COLOR_MATCH,
+            color,
+        ));
+        last_end = end;
+    }
+    highlighted.push_str(&String::from_utf8_lossy(&line[last_end..]));
+
+    // Vim wants nothing but the line after the last colon.
+    match (prefix.is_empty(), format.vimgrep) {
+        (true, _) => highlighted,
+        (false, true) => format!("{}:{highlighted}", prefix.join(":")),
+        (false, false) => format!("{}: {highlighted}", prefix.join(":")),
+    }
+}
+
+// Formats a matched line with the column of its first match.
+fn format_matched_line(matched_line: &MatchedLine, format: LineFormat, color: bool) -> String {
+    let start = matched_line.matches.first().map_or(0, |&(start, _)| start);
+
+    format_line_at(matched_line, format, start, matched_line.offset, color)
+}
+
+// In `vimgrep` format, a line with several matches is printed once for each of them.
+fn format_matched_lines(
+    matched_line: &MatchedLine,
+    format: LineFormat,
+    color: bool,
+) -> Vec<String> {
+    if !format.vimgrep || matched_line.binary || matched_line.matches.is_empty() {
+        return vec![format_matched_line(matched_line, format, color)];
+    }
+
+    matched_line
+        .matches
+        .iter()
+        .map(|&(start, _)| format_line_at(matched_line, format, start, matched_line.offset, color))
+        .collect()
+}
+
+// In the modes listing files, only the file name is printed, in the same color as elsewhere.
+fn format_file_name(file_name: &str, color: bool) -> String {
+    paint(file_name, COLOR_FILE_NAME, color)
+}
 
-        if matched {
-            out_channel.send(matched_line).unwrap();
+// Every pattern as a regular expression. Extracting or substituting matches needs one even in
+// literal mode, so in that case we escape the pattern. The whole match is then still available as
+// `$0`.
+fn pattern_sources(options: &Options) -> impl Iterator<Item = Cow<'_, str>> {
+    options.patterns.iter().map(move |pattern| {
+        if options.use_regexp_mode {
+            Cow::Borrowed(pattern.as_str())
+        } else {
+            Cow::Owned(regex::escape(pattern))
         }
+    })
+}
+
+// Several patterns are combined into a single alternation, which finds the same matches as a
+// `RegexSet` would, but also tells us where they are. Where several patterns match at the same
+// position, the first one wins. The groups are non-capturing, so `$1` still refers to the first
+// capture group of the patterns.
+fn combined_pattern(options: &Options) -> String {
+    let sources: Vec<_> = pattern_sources(options).collect();
+
+    if sources.len() == 1 {
+        return sources[0].to_string();
+    }
+
+    let alternatives: Vec<String> = sources
+        .iter()
+        .map(|source| format!("(?:{source})"))
+        .collect();
+    alternatives.join("|")
+}
+
+// A pattern that is not a valid regular expression is a mistake of the user, not a bug, so we
+// report it like any other error rather than panicking.
+fn invalid_pattern(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+// Every regex is built the same way, so that the search, the replacement and the JSON events all
+// agree on what matches. As we search many lines at once, `multi_line` makes `^` and `$` match at
+// every line boundary, and `crlf` makes `$` match before "\r\n" as well.
+fn compile_regex(source: &str, options: &Options) -> io::Result<Regex> {
+    RegexBuilder::new(source)
+        .multi_line(true)
+        .crlf(true)
+        .case_insensitive(options.ignore_case)
+        .build()
+        .map_err(invalid_pattern)
+}
+
+fn pattern_regex(options: &Options) -> io::Result<Regex> {
+    compile_regex(&combined_pattern(options), options)
+}
+
+// The patterns compiled one by one, so that we can tell which one a match belongs to.
+fn pattern_regexes(options: &Options) -> io::Result<Vec<Regex>> {
+    pattern_sources(options)
+        .map(|source| compile_regex(&source, options))
+        .collect()
+}
+
+// Substitutes every match in `line` with the expanded `template`, and returns the new line along
+// with the spans of the substituted parts, so that they can be highlighted just like matches.
+fn replace_matches(re: &Regex, line: &[u8], template: &[u8]) -> (Vec<u8>, Vec<(usize, usize)>) {
+    let mut replaced = Vec::with_capacity(line.len());
+    let mut spans = Vec::new();
+    let mut last_end = 0;
+
+    for captures in re.captures_iter(line) {
+        let whole = captures.get(0).unwrap();
+
+        replaced.extend_from_slice(&line[last_end..whole.start()]);
+        let start = replaced.len();
+        captures.expand(template, &mut replaced);
+        spans.push((start, replaced.len()));
+        last_end = whole.end();
     }
+    replaced.extend_from_slice(&line[last_end..]);
+
+    (replaced, spans)
 }
 
-// The third function performs the output operations, receiving the relevant lines on its
-// `in_channel`.
-fn output_lines(options: Arc<Options>, in_channel: Receiver<MatchedLine>) {
+// The last function performs the output operations, receiving the relevant lines on its
+// `in_channel`. Everything goes through the same lock of stdout, and writing may fail, like when
+// the reader closed the pipe. So may sorting, if it cannot write its temporary files. Files that
+// could not be searched are reported, and left out.
+fn output_lines(options: Arc<Options>, in_channel: &mut Batches) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    let color = options.color.should_color();
+    let format = options.line_format;
+    let stats = in_channel.stats.clone();
+    stats.start_output();
+    let in_channel = in_channel.filter_map(|matched_line| {
+        matched_line
+            .map_err(|error| eprintln!("rgrep: {error}"))
+            .ok()
+    });
+
     match options.output_mode {
         Print => {
             // Here, we just print every line we see.
-            for matched_line in in_channel.iter() {
-                let MatchedLine {
-                    file_name,
-                    number,
-                    line,
-                } = matched_line;
-
-                println!("{file_name}:{number}: {line}");
+            let mut printer = Printer::new(&mut out, format, color);
+
+            for matched_line in in_channel {
+                printer.matched(matched_line)?;
             }
+            printer.finish()?;
         }
         Count => {
             // We are supposed to count the number of matching lines. There's a convenient iterator
             // adapter that we can use for this job.
-            let count = in_channel.iter().count();
+            let count = in_channel.count();
+
+            writeln!(out, "{} hits for {}.", count, options.patterns.join(", "))?;
+        }
+        CountPerFile => {
+            // The lines of a file arrive one after the other, so we count until the file changes.
+            // A file given twice is counted twice, so we go by its index rather than its name.
+            let mut current: Option<(usize, Arc<String>, usize)> = None;
+
+            for matched_line in in_channel {
+                match current {
+                    Some((file_index, _, ref mut count))
+                        if file_index == matched_line.file_index =>
+                    {
+                        *count += 1;
+                    }
+                    _ => {
+                        if let Some((_, file_name, count)) = current.take() {
+                            writeln!(out, "{}:{count}", format_file_name(&file_name, color))?;
+                        }
+                        current = Some((matched_line.file_index, matched_line.file_name, 1));
+                    }
+                }
+            }
+
+            if let Some((_, file_name, count)) = current {
+                writeln!(out, "{}:{count}", format_file_name(&file_name, color))?;
+            }
+        }
+        FilesWithMatches | FilesWithoutMatch => {
+            // The workers send a single line for every file to be listed.
+            for matched_line in in_channel {
+                writeln!(out, "{}", format_file_name(&matched_line.file_name, color))?;
+            }
+        }
+        Json => json::print_events(
+            &mut out,
+            &options.patterns,
+            &pattern_regexes(&options)?,
+            // The statistics go into the summary.
+            options.stats.then_some(&*stats),
+            in_channel,
+        )?,
+        SortAndPrint(order) => {
+            // We are asked to sort the matching lines before printing. So let's collect them all,
+            // in memory as far as `sort_memory` allows, and in temporary files beyond that. As the
+            // order breaks all ties, it does not matter that `sort` is not stable.
+            let mut sorter = ExternalSorter::new(order, options.sort_memory);
+
+            for matched_line in in_channel {
+                sorter.push(matched_line)?;
+            }
+
+            for matched_line in sorter.finish()? {
+                for line in format_matched_lines(&matched_line?, format, color) {
+                    writeln!(out, "{line}")?;
+                }
+            }
+        }
+        OnlyMatching => {
+            // Every match gets a line of its own, so we turn each span into a "line" that is
+            // entirely matched. Its column and offset are still those of the match in the file.
+            // Empty matches, like those of `x*`, have nothing to show, so they are left out.
+            for matched_line in in_channel {
+                if matched_line.binary {
+                    writeln!(out, "{}", format_matched_line(&matched_line, format, color))?;
+                    continue;
+                }
+
+                for &(start, end) in matched_line.matches.iter() {
+                    if start == end {
+                        continue;
+                    }
 
-            println!("{} hits for {}.", count, options.pattern);
+                    let only_match = MatchedLine {
+                        file_name: matched_line.file_name.clone(),
+                        file_index: matched_line.file_index,
+                        number: matched_line.number,
+                        offset: matched_line.offset,
+                        line: matched_line.line[start..end].to_vec(),
+                        matches: vec![(0, end - start)],
+                        binary: false,
+                    };
+                    let offset = matched_line.offset + start;
+
+                    writeln!(
+                        out,
+                        "{}",
+                        format_line_at(&only_match, format, start, offset, color)
+                    )?;
+                }
+            }
         }
-        SortAndPrint => {
-            // We are asked to sort the matching lines before printing. So let's collect them all
-            // in a local vector...
-            let mut matched_lines: Vec<_> = in_channel.iter().collect();
+        Replace(ref template) => {
+            let re = pattern_regex(&options)?;
 
-            sort(&mut matched_lines);
+            for mut matched_line in in_channel {
+                if !matched_line.binary {
+                    let (line, matches) =
+                        replace_matches(&re, &matched_line.line, template.as_bytes());
 
-            for matched_line in matched_lines {
-                let MatchedLine {
-                    file_name,
-                    number,
-                    line,
-                } = matched_line;
+                    matched_line.line = line;
+                    matched_line.matches = matches;
+                }
 
-                println!("{file_name}:{number}: {line}");
+                for line in format_matched_lines(&matched_line, format, color) {
+                    writeln!(out, "{line}")?;
+                }
             }
         }
+        Quiet => {
+            // The stream of lines ends after the first one, we just wait for it.
+            in_channel.for_each(drop);
+        }
+        // Files are rewritten and watched by `run` directly, without these threads.
+        ReplaceInPlace { .. } | Watch => unreachable!(),
     }
+
+    if options.stats && !matches!(options.output_mode, Json) {
+        stats.print(&mut out)?;
+    }
+
+    out.flush()
 }
 
-// With the operations of the three threads defined, we can now implement a function that performs
-// grepping according to some given options.
-pub fn run(options: Options) {
-    // We move the `options` into an `Arc`, as that's what the thread workers expect.
-    let options = Arc::new(options);
+// A running search yields the matching lines of all files, in the order of the files. The search
+// runs in threads of its own: the workers and the collector. If the lines are not all taken,
+// dropping the `Matches` cancels the search. Either way, it waits for the threads to finish. The
+// search does not start at all if the patterns are invalid. A file that cannot be searched shows up
+// as an error in its place among the lines, and the search goes on with the other files.
+pub struct Matches {
+    batches: Batches,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Matches {
+    fn start(options: Arc<Options>) -> io::Result<Self> {
+        let matcher = Arc::new(Matcher::new(&options)?);
+        let progress = Arc::new(Progress::default());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Stats::new());
+
+        // This sets up the channels. We use `sync_channel`s with small buffers to avoid needlessly
+        // filling RAM. Only the channel returning empty batches to the workers is shared by all of
+        // them, so it needs a `Mutex`.
+        let (file_sender, file_receiver) = sync_channel(options.threads * 4);
+        let (ordered_sender, ordered_receiver) = sync_channel(16);
+        let (recycle_sender, recycle_receiver) = sync_channel(16);
+        let recycled = Arc::new(Mutex::new(recycle_receiver));
+
+        // Spawn the workers: `thread::spawn` takes a closure that is run in a new thread. Every
+        // worker gets its own clone of the sending end of the channel.
+        let mut threads: Vec<_> = (0..options.threads.max(1))
+            .map(|_| {
+                let options = options.clone();
+                let matcher = matcher.clone();
+                let progress = progress.clone();
+                let cancelled = cancelled.clone();
+                let stats = stats.clone();
+                let file_sender = file_sender.clone();
+                let recycled = recycled.clone();
+
+                thread::spawn(move || {
+                    search_files(
+                        options,
+                        matcher,
+                        progress,
+                        cancelled,
+                        stats,
+                        file_sender,
+                        recycled,
+                    )
+                })
+            })
+            .collect();
+        // Our own sending end has to go, or the collector would never see the channel close.
+        drop(file_sender);
+
+        // Same with the collector thread.
+        threads.push(thread::spawn(move || {
+            collect_in_order(file_receiver, ordered_sender, progress)
+        }));
 
-    // This sets up the channels. We use a `sync_channel` with buffer-size of 16 to avoid needlessly
-    // filling RAM.
-    let (line_sender, line_receiver) = sync_channel(16);
-    let (filtered_sender, filtered_receiver) = sync_channel(16);
+        // In quiet mode, a single line is all we need to know.
+        let batches = Batches {
+            in_channel: ordered_receiver,
+            recycle: recycle_sender,
+            current: Vec::new(),
+            remaining: match options.output_mode {
+                Quiet => Some(1),
+                _ => options.max_total,
+            },
+            cancelled,
+            seen: 0,
+            failed: 0,
+            stats,
+        };
+
+        Ok(Matches { batches, threads })
+    }
+}
 
-    // Spawn the read thread: `thread::spawn` takes a closure that is run in a new thread.
-    let options1 = options.clone();
-    let handle1 = thread::spawn(move || read_files(options1, line_sender));
+impl Iterator for Matches {
+    type Item = io::Result<MatchedLine>;
 
-    // Same with the filter thread.
-    let options2 = options.clone();
-    let handle2 = thread::spawn(move || filter_lines(options2, line_receiver, filtered_sender));
+    fn next(&mut self) -> Option<io::Result<MatchedLine>> {
+        self.batches.next()
+    }
+}
 
-    // And the output thread.
-    let options3 = options.clone();
-    let handle3 = thread::spawn(move || output_lines(options3, filtered_receiver));
+impl Drop for Matches {
+    fn drop(&mut self) {
+        // Closing our end of the channel makes the collector stop, and then the workers.
+        self.batches.cancelled.store(true, atomic::Ordering::SeqCst);
+        self.batches.in_channel = sync_channel(0).1;
 
-    // Finally, wait until all three threads did their job.
-    handle1.join().unwrap();
-    handle2.join().unwrap();
-    handle3.join().unwrap();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+// How often we look for new lines in watch mode.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+// With the operations of the threads defined, we can now implement a function that performs
+// grepping according to some given options. Errors that should make the whole program fail are
+// returned to the caller; otherwise we return whether anything matched. Files that could not be
+// searched make us fail at the end, just like `grep`, unless a quiet search found what it wanted.
+pub fn run(options: Options) -> io::Result<bool> {
+    if let ReplaceInPlace {
+        ref template,
+        dry_run,
+    } = options.output_mode
+    {
+        let re = pattern_regex(&options)?;
+
+        return in_place::rewrite_files(&options, &re, template.as_bytes(), dry_run);
+    }
+
+    if let Watch = options.output_mode {
+        let color = options.color.should_color();
+        let mut printer = Printer::new(io::stdout().lock(), options.line_format, color);
+
+        return watch::watch(Arc::new(options), WATCH_INTERVAL, &mut printer);
+    }
+
+    // We move the `options` into an `Arc`, as that's what the thread workers expect.
+    let options = Arc::new(options);
+    let mut matches = Matches::start(options.clone())?;
+
+    // The output runs in a thread of its own, too. Once it is done, dropping `matches` waits for
+    // all other threads. Should the output thread panic, we fail like on any other error.
+    let output = thread::spawn(move || {
+        let quiet = matches!(options.output_mode, Quiet);
+        output_lines(options, &mut matches.batches)?;
+
+        let (seen, failed) = (matches.batches.seen, matches.batches.failed);
+        if failed > 0 && !(quiet && seen > 0) {
+            return Err(io::Error::other(format!(
+                "failed to search {failed} file(s)"
+            )));
+        }
+        Ok(seen > 0)
+    });
+
+    output
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("the output failed")))
 }
 
 // Now we have all the pieces together for testing our rgrep with some hard-coded options.
@@ -218,11 +1137,443 @@ pub fn main() {
             "src/part11.rs".to_string().into(),
             "src/part12.rs".to_string().into(),
         ],
-        pattern: "let".to_string(),
-        output_mode: Print,
-        use_regexp_mode: false,
+        threads: 2,
+        color: ColorChoice::Auto,
+        ..Options::new(["let"])
     };
-    run(options);
+    run(options).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use part13::regex::bytes::Regex;
+    use part13::{
+        collect_in_order, format_matched_line, format_matched_lines, replace_matches, run,
+        search_files, Batches, FileBatch, LineFormat, MatchedLine, Matcher, Options, OutputMode,
+        Progress, SortKey, SortOrder, SortedLine, Stats,
+    };
+    use part14::sort;
+    use std::sync::atomic::{self, AtomicBool};
+    use std::sync::mpsc::sync_channel;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use std::{fs, io, thread};
+
+    fn matched_line(file_name: &str, number: usize) -> MatchedLine {
+        MatchedLine {
+            file_name: file_name.to_string().into(),
+            file_index: 0,
+            number,
+            offset: 0,
+            line: vec![],
+            matches: vec![],
+            binary: false,
+        }
+    }
+
+    #[test]
+    fn test_matched_line_partial_ord() {
+        let mut lines = vec![
+            MatchedLine {
+                file_name: "file1".to_string().into(),
+                file_index: 0,
+                line: b"b".to_vec(),
+                number: 1,
+                offset: 0,
+                matches: vec![],
+                binary: false,
+            },
+            MatchedLine {
+                file_name: "file2".to_string().into(),
+                file_index: 0,
+                line: b"a".to_vec(),
+                number: 2,
+                offset: 0,
+                matches: vec![],
+                binary: false,
+            },
+        ];
+
+        sort(&mut lines);
+
+        let expected = vec![
+            MatchedLine {
+                file_name: "file2".to_string().into(),
+                file_index: 0,
+                line: b"a".to_vec(),
+                number: 2,
+                offset: 0,
+                matches: vec![],
+                binary: false,
+            },
+            MatchedLine {
+                file_name: "file1".to_string().into(),
+                file_index: 0,
+                line: b"b".to_vec(),
+                number: 1,
+                offset: 0,
+                matches: vec![],
+                binary: false,
+            },
+        ];
+
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let line = |file_name: &str, number, line: &[u8]| MatchedLine {
+            line: line.to_vec(),
+            ..matched_line(file_name, number)
+        };
+        let sorted = |key, reverse| {
+            let order = SortOrder { key, reverse };
+            let mut sorted_lines: Vec<_> = vec![
+                line("b", 3, b"x"),
+                line("a", 7, b"y"),
+                line("b", 1, b"y"),
+                line("a", 2, b"x"),
+            ]
+            .into_iter()
+            .map(|matched_line| SortedLine {
+                order,
+                matched_line,
+            })
+            .collect();
+
+            sort(&mut sorted_lines);
+            sorted_lines
+                .iter()
+                .map(|sorted| {
+                    format!(
+                        "{}{}",
+                        sorted.matched_line.file_name, sorted.matched_line.number
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Ties are always broken by file and line number, even in reverse order.
+        assert_eq!(sorted(SortKey::Content, false), ["a2", "b3", "a7", "b1"]);
+        assert_eq!(sorted(SortKey::Content, true), ["a7", "b1", "a2", "b3"]);
+        assert_eq!(sorted(SortKey::Path, true), ["b1", "b3", "a2", "a7"]);
+        assert_eq!(sorted(SortKey::Line, false), ["b1", "a2", "b3", "a7"]);
+
+        // Lines with the same content from different files are not equal.
+        assert!(line("a", 2, b"x") != line("b", 2, b"x"));
+        assert!(line("a", 2, b"x") == line("a", 2, b"x"));
+    }
+
+    #[test]
+    fn test_format_matched_line_color() {
+        let matched_line = MatchedLine {
+            file_name: "file".to_string().into(),
+            file_index: 0,
+            line: b"let a = let_b;".to_vec(),
+            number: 3,
+            offset: 40,
+            matches: vec![(0, 3), (8, 11)],
+            binary: false,
+        };
+
+        let binary_line = MatchedLine {
+            file_name: "a.out".to_string().into(),
+            file_index: 0,
+            line: b"\x7fELF\x00let".to_vec(),
+            number: 1,
+            offset: 0,
+            matches: vec![(5, 8)],
+            binary: true,
+        };
+
+        let default = LineFormat::default();
+
+        assert_eq!(
+            format_matched_line(&matched_line, default, false),
+            "file:3: let a = let_b;"
+        );
+        assert_eq!(
+            format_matched_line(&matched_line, default, true),
+            "\x1b[35mfile\x1b[0m:\x1b[32m3\x1b[0m: \x1b[1;31mlet\x1b[0m a = \x1b[1;31mlet\x1b[0m_b;"
+        );
+        assert_eq!(
+            format_matched_line(&binary_line, default, true),
+            "Binary file a.out matches"
+        );
+    }
+
+    #[test]
+    fn test_line_format() {
+        let matched_line = MatchedLine {
+            file_name: "file".to_string().into(),
+            file_index: 0,
+            line: b"let a = let_b;".to_vec(),
+            number: 3,
+            offset: 40,
+            matches: vec![(0, 3), (8, 11)],
+            binary: false,
+        };
+        let format = |file_name, line_number, column, byte_offset| LineFormat {
+            file_name,
+            line_number,
+            column,
+            byte_offset,
+            vimgrep: false,
+        };
+
+        assert_eq!(
+            format_matched_line(&matched_line, format(false, true, true, true), false),
+            "3:1:40: let a = let_b;"
+        );
+        assert_eq!(
+            format_matched_line(&matched_line, format(true, false, false, false), false),
+            "file: let a = let_b;"
+        );
+        assert_eq!(
+            format_matched_line(&matched_line, format(false, false, false, false), false),
+            "let a = let_b;"
+        );
+
+        // Every match gets a line of its own, whatever else is asked for.
+        let vimgrep = LineFormat {
+            vimgrep: true,
+            ..format(false, false, false, false)
+        };
+        assert_eq!(
+            format_matched_lines(&matched_line, vimgrep, false),
+            ["file:3:1:let a = let_b;", "file:3:9:let a = let_b;"]
+        );
+    }
+
+    #[test]
+    fn test_replace_matches() {
+        let re = Regex::new(r"(?P<key>\w+)=(\d+)").unwrap();
+
+        assert_eq!(
+            replace_matches(&re, b"a=1, bc=22", b"${key}:$2"),
+            (b"a:1, bc:22".to_vec(), vec![(0, 3), (5, 10)])
+        );
+        assert_eq!(
+            replace_matches(&re, b"nothing here", b"$1"),
+            (b"nothing here".to_vec(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_collect_in_order() {
+        let (file_sender, file_receiver) = sync_channel(8);
+        let (ordered_sender, ordered_receiver) = sync_channel(8);
+        let progress = Arc::new(Progress::default());
+        let collector = {
+            let progress = progress.clone();
+            thread::spawn(move || collect_in_order(file_receiver, ordered_sender, progress))
+        };
+        let batch = |index, lines, last| FileBatch {
+            index,
+            lines,
+            last,
+            error: None,
+        };
+
+        // The workers may finish in any order, a file may span several batches and it may have no
+        // matches at all.
+        file_sender
+            .send(batch(2, vec![matched_line("c", 0)], true))
+            .unwrap();
+        file_sender
+            .send(batch(0, vec![matched_line("a", 0)], false))
+            .unwrap();
+        file_sender.send(batch(1, vec![], true)).unwrap();
+        file_sender
+            .send(batch(0, vec![matched_line("a", 5)], true))
+            .unwrap();
+        file_sender
+            .send(batch(3, vec![matched_line("d", 1)], true))
+            .unwrap();
+        // A file that failed half-way has its error after its lines.
+        file_sender
+            .send(FileBatch {
+                error: Some(io::Error::other("e: unreadable")),
+                ..batch(4, vec![matched_line("e", 2)], true)
+            })
+            .unwrap();
+        drop(file_sender);
+
+        let order: Vec<_> = ordered_receiver
+            .iter()
+            .flat_map(|lines| match lines {
+                Ok(lines) => lines
+                    .into_iter()
+                    .map(|line| (line.file_name.to_string(), line.number))
+                    .collect(),
+                Err(error) => vec![(error.to_string(), 0)],
+            })
+            .collect();
+        collector.join().unwrap();
+
+        assert_eq!(
+            order,
+            vec![
+                ("a".to_string(), 0),
+                ("a".to_string(), 5),
+                ("c".to_string(), 0),
+                ("d".to_string(), 1),
+                ("e".to_string(), 2),
+                ("e: unreadable".to_string(), 0),
+            ]
+        );
+        // All files are done, so all their lines are counted.
+        assert_eq!(progress.lines_done.load(atomic::Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_progress_window() {
+        let progress = Arc::new(Progress::default());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let started = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (progress, cancelled, started) =
+                (progress.clone(), cancelled.clone(), started.clone());
+            thread::spawn(move || {
+                progress.wait_for(5, 4, &cancelled);
+                started.store(true, atomic::Ordering::SeqCst);
+            })
+        };
+
+        // File 5 has to wait until the collector is at file 2.
+        progress.wait_for(3, 4, &cancelled);
+        progress.move_on(1);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!started.load(atomic::Ordering::SeqCst));
+        progress.move_on(2);
+        worker.join().unwrap();
+        assert!(started.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_batches_remaining() {
+        let (ordered_sender, ordered_receiver) = sync_channel(8);
+        let (recycle_sender, _) = sync_channel(8);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut batches = Batches {
+            in_channel: ordered_receiver,
+            recycle: recycle_sender,
+            current: Vec::new(),
+            remaining: Some(3),
+            cancelled: cancelled.clone(),
+            seen: 0,
+            failed: 0,
+            stats: Arc::new(Stats::new()),
+        };
+
+        ordered_sender
+            .send(Ok(vec![matched_line("a", 0), matched_line("a", 1)]))
+            .unwrap();
+        ordered_sender
+            .send(Ok(vec![matched_line("b", 0), matched_line("b", 1)]))
+            .unwrap();
+
+        // The stream ends after three lines without waiting for more, and cancels the search.
+        assert_eq!(batches.by_ref().count(), 3);
+        assert_eq!(batches.seen, 3);
+        assert!(cancelled.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_search_files_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<_> = [("a.txt", "let\nlet\n"), ("b.txt", "const\n")]
+            .iter()
+            .map(|(name, contents)| {
+                let path = dir.path().join(name);
+                fs::write(&path, contents).unwrap();
+                path.to_str().unwrap().to_string().into()
+            })
+            .collect();
+
+        // Lists the lines a single worker sends for every file.
+        let search = |output_mode| {
+            let options = Options {
+                threads: 1,
+                files: files.clone(),
+                output_mode,
+                ..Options::new(["let"])
+            };
+            let (file_sender, file_receiver) = sync_channel(16);
+            let (_, recycle_receiver) = sync_channel(1);
+            let matcher = Matcher::new(&options).unwrap();
+            search_files(
+                Arc::new(options),
+                Arc::new(matcher),
+                Arc::new(Progress::default()),
+                Arc::new(AtomicBool::new(false)),
+                Arc::new(Stats::new()),
+                file_sender,
+                Arc::new(Mutex::new(recycle_receiver)),
+            );
+
+            file_receiver
+                .iter()
+                .flat_map(|batch| batch.lines)
+                .map(|line| {
+                    (
+                        line.file_name[dir.path().to_str().unwrap().len()..].to_string(),
+                        line.number,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Listing a file with matches stops at the first one, and a file without gets an empty
+        // line of its own.
+        assert_eq!(
+            search(OutputMode::Print),
+            [("/a.txt".to_string(), 1), ("/a.txt".to_string(), 2)]
+        );
+        assert_eq!(
+            search(OutputMode::FilesWithMatches),
+            [("/a.txt".to_string(), 1)]
+        );
+        assert_eq!(
+            search(OutputMode::FilesWithoutMatch),
+            [("/b.txt".to_string(), 0)]
+        );
+    }
+
+    // Compares a single worker, which searches the files one after the other like the original
+    // reader/filter pipeline did, with one worker per CPU. Run it with
+    // `cargo test --release -- --ignored --nocapture bench_threads`.
+    #[test]
+    #[ignore]
+    fn bench_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<_> = (0..64)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}.txt"));
+                let contents: String = (0..20_000)
+                    .map(|n| format!("line {n} of file {i}: lorem ipsum dolor sit amet\n"))
+                    .collect();
+                fs::write(&path, contents).unwrap();
+                path.to_str().unwrap().to_string().into()
+            })
+            .collect();
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+
+        for threads in [1, cpus] {
+            let options = Options {
+                use_regexp_mode: true,
+                threads,
+                files: files.clone(),
+                output_mode: OutputMode::Count,
+                ..Options::new([r"of file \d+7: lorem"])
+            };
+            let start = Instant::now();
+            run(options).unwrap();
+            println!("{threads} thread(s): {:?}", start.elapsed());
+        }
+    }
 }
 
 // **Exercise 13.1**: Change rgrep such that it prints not only the matching lines, but also the
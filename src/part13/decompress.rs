@@ -0,0 +1,130 @@
+// Rotated logs are usually compressed. With `-z`, we look at the first bytes of every input, and
+// if they are the magic number of a compression format, we decompress the input on the fly while
+// searching it. Only gzip is supported, through the pure-Rust backend of `flate2`; the other common
+// formats are at least recognized, so we can say why we cannot search them.
+
+extern crate flate2;
+
+use self::flate2::read::MultiGzDecoder;
+use std::io::{self, Read};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+// Whether `start` could still turn out to be a magic number once more bytes come in. The bzip2 one
+// is followed by a digit for the block size, which we check as well.
+fn undecided(start: &[u8]) -> bool {
+    let could_be = |magic: &[u8], length: usize| {
+        start.len() < length && magic.starts_with(&start[..start.len().min(magic.len())])
+    };
+
+    could_be(GZIP_MAGIC, GZIP_MAGIC.len())
+        || could_be(BZIP2_MAGIC, BZIP2_MAGIC.len() + 1)
+        || could_be(XZ_MAGIC, XZ_MAGIC.len())
+}
+
+// Wraps `reader` in a decompressor if its contents are compressed. The bytes we looked at to find
+// out are put back in front of the rest, so nothing is lost for uncompressed input. We only read
+// again while the first bytes could still be a magic number, so that a pipe giving us a line of
+// plain text is searched right away rather than when more comes in.
+pub fn decompressed<'a>(mut reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    let mut magic = [0; 6];
+    let mut length = 0;
+
+    while undecided(&magic[..length]) {
+        match reader.read(&mut magic[length..]) {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    let magic = &magic[..length];
+    let format = if magic.starts_with(BZIP2_MAGIC) && magic.len() > 3 && magic[3].is_ascii_digit() {
+        "bzip2"
+    } else if magic.starts_with(XZ_MAGIC) {
+        "xz"
+    } else {
+        let reader = io::Cursor::new(magic.to_vec()).chain(reader);
+
+        // A gzip file may consist of several compressed members one after the other, which is
+        // what you get from `cat a.gz b.gz`. `MultiGzDecoder` reads all of them.
+        if magic.starts_with(GZIP_MAGIC) {
+            return Ok(Box::new(MultiGzDecoder::new(reader)));
+        }
+        return Ok(Box::new(reader));
+    };
+
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{format}-compressed input is not supported, only gzip is"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompressed;
+    use super::flate2::write::GzEncoder;
+    use super::flate2::Compression;
+    use std::io::{self, Read, Write};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Hands out its chunks one read at a time, like a pipe does. Reading any further would block.
+    struct Pipe(Vec<&'static [u8]>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            assert!(!self.0.is_empty(), "read from a pipe that would block");
+            let chunk = self.0.remove(0);
+
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn read_all(input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        decompressed(input)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_decompressed() {
+        // Plain input comes out unchanged, even if it is shorter than a magic number.
+        assert_eq!(read_all(b"plain text\n"), b"plain text\n");
+        assert_eq!(read_all(b"BZ"), b"BZ");
+
+        // Once the first bytes cannot be a magic number, we do not wait for more.
+        assert!(decompressed(Pipe(vec![b"a"])).is_ok());
+        assert!(decompressed(Pipe(vec![b"B", b"Zx"])).is_ok());
+        let error = decompressed(Pipe(vec![&[0xfd], b"7zXZ", &[0]]))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "xz-compressed input is not supported, only gzip is"
+        );
+
+        let mut members = gzip(b"first\n");
+        members.extend(gzip(b"second\n"));
+        assert_eq!(read_all(&members), b"first\nsecond\n");
+
+        let error = decompressed(&b"BZh91AY&SY"[..]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "bzip2-compressed input is not supported, only gzip is"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+// Listing every file on the command line gets tedious quickly, so rgrep also accepts directories
+// and searches all files below them. Which files are searched can be narrowed down with globs and
+// with file types, which are just named lists of globs. The filter applies to the files given on
+// the command line as well as to the files found in directories; standard input is always searched.
+
+extern crate globset;
+extern crate walkdir;
+
+use self::globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use self::walkdir::{DirEntry, WalkDir};
+use std::sync::Arc;
+
+// The file types known to `-t` and `-T`, with the globs of the files that belong to them.
+const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("c", &["*.c", "*.h"]),
+    ("cpp", &["*.cpp", "*.cc", "*.cxx", "*.hpp", "*.hh", "*.hxx"]),
+    ("css", &["*.css", "*.scss"]),
+    ("go", &["*.go"]),
+    ("html", &["*.html", "*.htm"]),
+    ("java", &["*.java"]),
+    ("js", &["*.js", "*.mjs", "*.cjs", "*.jsx"]),
+    ("json", &["*.json"]),
+    ("lock", &["*.lock"]),
+    ("markdown", &["*.md", "*.markdown"]),
+    ("py", &["*.py", "*.pyi"]),
+    ("rust", &["*.rs"]),
+    ("sh", &["*.sh", "*.bash", "*.zsh"]),
+    ("toml", &["*.toml"]),
+    ("ts", &["*.ts", "*.tsx"]),
+    ("txt", &["*.txt"]),
+    ("yaml", &["*.yaml", "*.yml"]),
+];
+
+// Decides which files get searched. A file has to match one of the included globs and one of the
+// included types, unless there are none of them, and none of the excluded globs and types.
+pub struct FileFilter {
+    include_globs: GlobSet,
+    exclude_globs: GlobSet,
+    include_types: GlobSet,
+    exclude_types: GlobSet,
+}
+
+// Globs without a slash, like `*.rs`, match the file name in any directory. Globs with a slash are
+// matched against the whole path, and `*` does not cross directories there.
+fn compile_glob(glob: &str) -> Result<Glob, String> {
+    let pattern = if glob.contains('/') {
+        glob.to_string()
+    } else {
+        format!("**/{glob}")
+    };
+
+    GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|error| format!("Invalid glob '{glob}': {}", error.kind()))
+}
+
+fn build_set<'a>(globs: impl Iterator<Item = &'a str>) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+
+    for glob in globs {
+        builder.add(compile_glob(glob)?);
+    }
+
+    builder.build().map_err(|error| error.to_string())
+}
+
+fn type_globs(types: &[&str]) -> Result<Vec<&'static str>, String> {
+    let mut globs = Vec::new();
+
+    for &name in types {
+        match FILE_TYPES.iter().find(|&&(type_name, _)| type_name == name) {
+            Some(&(_, type_globs)) => globs.extend_from_slice(type_globs),
+            None => {
+                let known: Vec<&str> = FILE_TYPES.iter().map(|&(name, _)| name).collect();
+                return Err(format!(
+                    "Unknown file type '{name}', expected one of {}",
+                    known.join(", ")
+                ));
+            }
+        }
+    }
+
+    Ok(globs)
+}
+
+impl FileFilter {
+    // Builds the filter from the `--glob` arguments, where a leading `!` turns a glob into an
+    // exclusion, and the names of the included and excluded file types.
+    pub fn new(globs: &[&str], types: &[&str], not_types: &[&str]) -> Result<Self, String> {
+        let (exclude, include): (Vec<&str>, Vec<&str>) =
+            globs.iter().partition(|glob| glob.starts_with('!'));
+
+        Ok(FileFilter {
+            include_globs: build_set(include.into_iter())?,
+            exclude_globs: build_set(exclude.iter().map(|glob| &glob[1..]))?,
+            include_types: build_set(type_globs(types)?.into_iter())?,
+            exclude_types: build_set(type_globs(not_types)?.into_iter())?,
+        })
+    }
+
+    // Paths below the current directory are matched without their leading "./", so that
+    // `src/*` applies to the files found in "." as well.
+    pub fn is_match(&self, path: &str) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+
+        (self.include_globs.is_empty() || self.include_globs.is_match(path))
+            && (self.include_types.is_empty() || self.include_types.is_match(path))
+            && !self.exclude_globs.is_match(path)
+            && !self.exclude_types.is_match(path)
+    }
+}
+
+// Hidden files and directories, like `.git`, are skipped while walking a directory. They can still
+// be searched by naming them on the command line.
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+}
+
+// Expands the paths from the command line into the list of files to search: directories are walked
+// recursively, in the order of their file names so that the output does not depend on the file
+// system. Paths that cannot be read are kept, so that searching them reports the error.
+pub fn discover_files(paths: &[&str], filter: &FileFilter) -> Vec<Arc<String>> {
+    let mut files = Vec::new();
+
+    for &path in paths {
+        if path == "-" {
+            files.push(path.to_string().into());
+            continue;
+        }
+
+        let walk = WalkDir::new(path)
+            .follow_links(true)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| !is_hidden(entry));
+
+        for entry in walk {
+            let file_name = match entry {
+                Ok(ref entry) if entry.file_type().is_dir() => continue,
+                Ok(entry) => entry.path().to_string_lossy().into_owned(),
+                Err(error) => match error.path() {
+                    Some(error_path) => error_path.to_string_lossy().into_owned(),
+                    None => continue,
+                },
+            };
+
+            if filter.is_match(&file_name) {
+                files.push(file_name.into());
+            }
+        }
+    }
+
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::{discover_files, FileFilter};
+    use std::fs;
+
+    #[test]
+    fn test_file_filter() {
+        let filter = FileFilter::new(&["!*.lock"], &[], &[]).unwrap();
+        assert!(filter.is_match("src/main.rs"));
+        assert!(!filter.is_match("Cargo.lock"));
+
+        let filter = FileFilter::new(&["src/*"], &["rust"], &[]).unwrap();
+        assert!(filter.is_match("./src/main.rs"));
+        assert!(!filter.is_match("src/part13/search.rs"));
+        assert!(!filter.is_match("src/notes.txt"));
+
+        let filter = FileFilter::new(&[], &[], &["rust", "toml"]).unwrap();
+        assert!(filter.is_match("README.md"));
+        assert!(!filter.is_match("./Cargo.toml"));
+
+        assert!(FileFilter::new(&[], &["klingon"], &[]).is_err());
+        assert!(FileFilter::new(&["[a"], &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_discover_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        for file in [
+            "b.rs",
+            "a.txt",
+            "src/nested/c.rs",
+            "src/a.rs",
+            ".git/config.rs",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+
+        let root = dir.to_str().unwrap();
+        let filter = FileFilter::new(&[], &["rust"], &[]).unwrap();
+        let files: Vec<String> = discover_files(&[root, "-"], &filter)
+            .iter()
+            .map(|file| file.strip_prefix(root).unwrap_or(file).to_string())
+            .collect();
+
+        assert_eq!(files, ["/b.rs", "/src/a.rs", "/src/nested/c.rs", "-"]);
+    }
+}
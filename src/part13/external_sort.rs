@@ -0,0 +1,377 @@
+// Sorting needs all matching lines before it can print the first one, which does not work if they
+// do not fit into memory. So we collect lines only up to a memory budget, sort them with
+// `part14::sort` and write the sorted run to a temporary file. At the end, the runs are merged: we
+// keep the next line of every run in a heap and always print the smallest one. If everything fits
+// into the budget, nothing is written at all. Every run keeps its file open until it is merged, so
+// if there are too many of them, they are first merged in groups into fewer, longer runs.
+
+extern crate tempfile;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::mem;
+use std::sync::Arc;
+use std::vec;
+
+use part13::{MatchedLine, SortOrder, SortedLine};
+use part14::sort;
+
+// A run has at least this many lines, however small the budget, so that we do not end up with a
+// file for every line.
+const MIN_RUN_LINES: usize = 256;
+
+// At most this many runs are merged at once, and thus open at the same time.
+const MAX_MERGE: usize = 64;
+
+// A rough idea of how much memory a line takes.
+fn line_size(matched_line: &MatchedLine) -> usize {
+    mem::size_of::<SortedLine>()
+        + matched_line.line.len()
+        + matched_line.matches.len() * mem::size_of::<(usize, usize)>()
+}
+
+pub struct ExternalSorter {
+    order: SortOrder,
+    budget: usize,
+    run: Vec<SortedLine>,
+    run_size: usize,
+    spilled: Vec<File>,
+    // File names are written as an index into this table, and shared again when reading them back.
+    file_names: Vec<Arc<String>>,
+    file_indices: HashMap<Arc<String>, usize>,
+}
+
+impl ExternalSorter {
+    pub fn new(order: SortOrder, budget: usize) -> Self {
+        ExternalSorter {
+            order,
+            budget,
+            run: Vec::new(),
+            run_size: 0,
+            spilled: Vec::new(),
+            file_names: Vec::new(),
+            file_indices: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, matched_line: MatchedLine) -> io::Result<()> {
+        self.run_size += line_size(&matched_line);
+        self.run.push(SortedLine {
+            order: self.order,
+            matched_line,
+        });
+
+        if self.run_size > self.budget && self.run.len() >= MIN_RUN_LINES {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    // Sorts the current run and writes it to a temporary file, which is deleted once we close it.
+    fn spill(&mut self) -> io::Result<()> {
+        sort(&mut self.run);
+
+        let mut writer = BufWriter::new(tempfile::tempfile()?);
+        for sorted_line in self.run.drain(..) {
+            let matched_line = sorted_line.matched_line;
+            let next_index = self.file_indices.len();
+            let name_index = *self
+                .file_indices
+                .entry(matched_line.file_name.clone())
+                .or_insert(next_index);
+            if name_index == self.file_names.len() {
+                self.file_names.push(matched_line.file_name.clone());
+            }
+
+            write_line(&mut writer, name_index, &matched_line)?;
+        }
+
+        self.spilled.push(rewound(writer)?);
+        self.run_size = 0;
+
+        Ok(())
+    }
+
+    // Merges the given runs: the heap holds the next line of every run.
+    fn merge(
+        &self,
+        files: Vec<File>,
+        file_names: &Arc<Vec<Arc<String>>>,
+    ) -> io::Result<SortedLines> {
+        let mut heap = BinaryHeap::new();
+        let mut runs = Vec::new();
+
+        for (index, file) in files.into_iter().enumerate() {
+            let mut run = RunReader {
+                reader: BufReader::new(file),
+                file_names: file_names.clone(),
+            };
+
+            if let Some(matched_line) = run.read_line()? {
+                heap.push(HeapEntry {
+                    order: self.order,
+                    matched_line,
+                    run: index,
+                });
+            }
+            runs.push(run);
+        }
+
+        Ok(SortedLines {
+            lines: Lines::Merged { heap, runs },
+        })
+    }
+
+    // Sorts what is left, and returns all lines in order.
+    pub fn finish(mut self) -> io::Result<SortedLines> {
+        if self.spilled.is_empty() {
+            sort(&mut self.run);
+            return Ok(SortedLines {
+                lines: Lines::InMemory(self.run.into_iter()),
+            });
+        }
+
+        if !self.run.is_empty() {
+            self.spill()?;
+        }
+
+        let file_names = Arc::new(mem::take(&mut self.file_names));
+
+        while self.spilled.len() > MAX_MERGE {
+            let mut spilled = mem::take(&mut self.spilled).into_iter();
+
+            loop {
+                let group: Vec<File> = spilled.by_ref().take(MAX_MERGE).collect();
+                if group.len() <= 1 {
+                    self.spilled.extend(group);
+                    break;
+                }
+
+                let mut writer = BufWriter::new(tempfile::tempfile()?);
+                for matched_line in self.merge(group, &file_names)? {
+                    let matched_line = matched_line?;
+                    let name_index = self.file_indices[&matched_line.file_name];
+                    write_line(&mut writer, name_index, &matched_line)?;
+                }
+                self.spilled.push(rewound(writer)?);
+            }
+        }
+
+        let spilled = mem::take(&mut self.spilled);
+        self.merge(spilled, &file_names)
+    }
+}
+
+// Finishes writing a run, and goes back to its start for reading it.
+fn rewound(writer: BufWriter<File>) -> io::Result<File> {
+    let mut file = writer.into_inner().map_err(|error| error.into_error())?;
+
+    file.rewind()?;
+    Ok(file)
+}
+
+// The lines of a run are written one after the other, every number in little endian.
+fn write_line(
+    writer: &mut impl Write,
+    name_index: usize,
+    matched_line: &MatchedLine,
+) -> io::Result<()> {
+    writer.write_all(&(name_index as u64).to_le_bytes())?;
+    writer.write_all(&(matched_line.file_index as u64).to_le_bytes())?;
+    writer.write_all(&(matched_line.number as u64).to_le_bytes())?;
+    writer.write_all(&(matched_line.offset as u64).to_le_bytes())?;
+    writer.write_all(&[matched_line.binary as u8])?;
+    writer.write_all(&(matched_line.line.len() as u64).to_le_bytes())?;
+    writer.write_all(&matched_line.line)?;
+    writer.write_all(&(matched_line.matches.len() as u64).to_le_bytes())?;
+    for &(start, end) in &matched_line.matches {
+        writer.write_all(&(start as u64).to_le_bytes())?;
+        writer.write_all(&(end as u64).to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+    file_names: Arc<Vec<Arc<String>>>,
+}
+
+impl RunReader {
+    fn read_number(&mut self) -> io::Result<usize> {
+        let mut bytes = [0; 8];
+
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes) as usize)
+    }
+
+    // Reads the next line of the run, or `None` at its end.
+    fn read_line(&mut self) -> io::Result<Option<MatchedLine>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let name_index = self.read_number()?;
+        let file_name = self.file_names[name_index].clone();
+        let file_index = self.read_number()?;
+        let number = self.read_number()?;
+        let offset = self.read_number()?;
+        let mut binary = [0];
+        self.reader.read_exact(&mut binary)?;
+        let mut line = vec![0; self.read_number()?];
+        self.reader.read_exact(&mut line)?;
+        let matches = (0..self.read_number()?)
+            .map(|_| Ok((self.read_number()?, self.read_number()?)))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Some(MatchedLine {
+            file_name,
+            file_index,
+            number,
+            offset,
+            line,
+            matches,
+            binary: binary[0] != 0,
+        }))
+    }
+}
+
+// `BinaryHeap` puts the largest element on top, so the heap entries compare the other way round.
+struct HeapEntry {
+    order: SortOrder,
+    matched_line: MatchedLine,
+    run: usize,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order
+            .compare(&other.matched_line, &self.matched_line)
+            .then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+// The lines come either straight from memory, or from merging the runs.
+enum Lines {
+    InMemory(vec::IntoIter<SortedLine>),
+    Merged {
+        heap: BinaryHeap<HeapEntry>,
+        runs: Vec<RunReader>,
+    },
+}
+
+pub struct SortedLines {
+    lines: Lines,
+}
+
+impl Iterator for SortedLines {
+    type Item = io::Result<MatchedLine>;
+
+    fn next(&mut self) -> Option<io::Result<MatchedLine>> {
+        match self.lines {
+            Lines::InMemory(ref mut lines) => {
+                lines.next().map(|sorted_line| Ok(sorted_line.matched_line))
+            }
+            Lines::Merged {
+                ref mut heap,
+                ref mut runs,
+            } => {
+                let entry = heap.pop()?;
+
+                // The smallest line is gone from the heap, so the next line of its run comes in.
+                match runs[entry.run].read_line() {
+                    Ok(Some(matched_line)) => heap.push(HeapEntry {
+                        order: entry.order,
+                        matched_line,
+                        run: entry.run,
+                    }),
+                    Ok(None) => {}
+                    Err(error) => return Some(Err(error)),
+                }
+
+                Some(Ok(entry.matched_line))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExternalSorter, Lines, MAX_MERGE, MIN_RUN_LINES};
+    use part13::{MatchedLine, SortKey, SortOrder};
+
+    // Enough lines for more runs than we merge at once.
+    const LINES: usize = (MAX_MERGE + 10) * MIN_RUN_LINES;
+
+    fn sorted(budget: usize) -> Vec<(String, usize)> {
+        let order = SortOrder {
+            key: SortKey::Content,
+            reverse: false,
+        };
+        let mut sorter = ExternalSorter::new(order, budget);
+
+        for number in 0..LINES {
+            sorter
+                .push(MatchedLine {
+                    file_name: format!("file{}", number % 3).into(),
+                    file_index: number % 3,
+                    number,
+                    offset: number * 10,
+                    line: format!("line {}", number * 7 % 100).into_bytes(),
+                    matches: vec![(0, 4)],
+                    binary: number % 100 == 0,
+                })
+                .unwrap();
+        }
+
+        if budget == 0 {
+            assert_eq!(sorter.spilled.len(), LINES / MIN_RUN_LINES);
+        }
+        let lines = sorter.finish().unwrap();
+        if budget == usize::MAX {
+            assert!(matches!(lines.lines, Lines::InMemory(_)));
+        }
+
+        lines
+            .map(|matched_line| {
+                let matched_line = matched_line.unwrap();
+                assert_eq!(matched_line.matches, [(0, 4)]);
+                assert_eq!(matched_line.binary, matched_line.number % 100 == 0);
+                assert_eq!(matched_line.offset, matched_line.number * 10);
+                assert_eq!(matched_line.file_index, matched_line.number % 3);
+                (matched_line.file_name.to_string(), matched_line.number)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_external_sorter() {
+        let in_memory = sorted(usize::MAX);
+
+        assert_eq!(in_memory.len(), LINES);
+        assert_eq!(
+            in_memory[..2],
+            [("file0".to_string(), 0), ("file0".to_string(), 300)]
+        );
+        // Budgets this small make a run of every `MIN_RUN_LINES` lines, more than we merge at once.
+        assert_eq!(sorted(1000), in_memory);
+        assert_eq!(sorted(0), in_memory);
+    }
+}
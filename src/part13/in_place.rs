@@ -0,0 +1,345 @@
+// Rewriting files in place does not fit the line-by-line pipeline of `run`: we need the complete
+// new contents of a file before we can replace the old one. So this mode works file by file, using
+// the same `replace_matches` as the `Replace` output mode. Like the search, it works on bytes, so
+// files that are not valid UTF-8 are rewritten without mangling them.
+
+extern crate tempfile;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use part13::regex::bytes::Regex;
+use part13::search::is_binary;
+use part13::{replace_matches, Options};
+
+// A line of a file, split into its content and its terminator ("\n", "\r\n" or nothing for a
+// last line without a newline), so that we match on the same text as the search does and still
+// write the original line endings back.
+fn split_lines(contents: &[u8]) -> Vec<(&[u8], &[u8])> {
+    contents
+        .split_inclusive(|&byte| byte == b'\n')
+        .map(|line| {
+            let content = line
+                .strip_suffix(b"\r\n")
+                .or_else(|| line.strip_suffix(b"\n"))
+                .unwrap_or(line);
+
+            (content, &line[content.len()..])
+        })
+        .collect()
+}
+
+// The result of running the replacement over a whole file: for every original line the line that
+// replaces it. As we replace line by line, the old and the new file correspond line by line, which
+// makes computing a diff easy.
+struct Rewrite<'a> {
+    old_lines: Vec<(&'a [u8], &'a [u8])>,
+    new_lines: Vec<Option<Vec<u8>>>,
+}
+
+impl<'a> Rewrite<'a> {
+    fn new(re: &Regex, template: &[u8], contents: &'a [u8]) -> Self {
+        let old_lines = split_lines(contents);
+        let new_lines = old_lines
+            .iter()
+            .map(|&(line, _)| {
+                let (replaced, _) = replace_matches(re, line, template);
+
+                if replaced != line {
+                    Some(replaced)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Rewrite {
+            old_lines,
+            new_lines,
+        }
+    }
+
+    fn is_changed(&self) -> bool {
+        self.new_lines.iter().any(Option::is_some)
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        let mut contents = Vec::new();
+
+        for (&(line, terminator), new_line) in self.old_lines.iter().zip(self.new_lines.iter()) {
+            contents.extend_from_slice(new_line.as_deref().unwrap_or(line));
+            contents.extend_from_slice(terminator);
+        }
+
+        contents
+    }
+
+    // Produces a unified diff with three lines of context, the format that `diff -u` and `patch`
+    // understand. The lines are copied as they are, so the diff applies to files that are not
+    // valid UTF-8, too.
+    fn unified_diff(&self, file_name: &str) -> Vec<u8> {
+        const CONTEXT: usize = 3;
+
+        let changed: Vec<usize> = (0..self.new_lines.len())
+            .filter(|&i| self.new_lines[i].is_some())
+            .collect();
+        let mut diff = format!("--- {file_name}\n+++ {file_name}\n").into_bytes();
+        let mut i = 0;
+
+        // Changes that are close enough for their context to overlap are merged into one hunk.
+        while i < changed.len() {
+            let mut j = i;
+            while j + 1 < changed.len() && changed[j + 1] - changed[j] <= 2 * CONTEXT {
+                j += 1;
+            }
+
+            let start = changed[i].saturating_sub(CONTEXT);
+            let end = (changed[j] + CONTEXT + 1).min(self.old_lines.len());
+            let mut body = Vec::new();
+            let mut new_count = 0;
+
+            for line_index in start..end {
+                let (old_line, terminator) = self.old_lines[line_index];
+
+                match self.new_lines[line_index] {
+                    None => {
+                        push_diff_line(&mut body, b' ', old_line, terminator);
+                        new_count += 1;
+                    }
+                    Some(ref new_line) => {
+                        push_diff_line(&mut body, b'-', old_line, terminator);
+                        // The template may contain newlines, so a single line can turn into
+                        // several.
+                        let new_lines: Vec<&[u8]> = new_line.split(|&byte| byte == b'\n').collect();
+                        for (k, part) in new_lines.iter().enumerate() {
+                            let part_terminator: &[u8] = if k + 1 < new_lines.len() {
+                                b"\n"
+                            } else {
+                                terminator
+                            };
+                            push_diff_line(&mut body, b'+', part, part_terminator);
+                        }
+                        new_count += new_lines.len();
+                    }
+                }
+            }
+
+            diff.extend_from_slice(
+                format!(
+                    "@@ -{},{} +{},{} @@\n",
+                    start + 1,
+                    end - start,
+                    start + 1 + self.line_growth_before(start),
+                    new_count
+                )
+                .as_bytes(),
+            );
+            diff.extend_from_slice(&body);
+            i = j + 1;
+        }
+
+        diff
+    }
+
+    // The number of lines the replacements before `line_index` added to the file.
+    fn line_growth_before(&self, line_index: usize) -> usize {
+        self.new_lines[..line_index]
+            .iter()
+            .flatten()
+            .map(|new_line| new_line.iter().filter(|&&byte| byte == b'\n').count())
+            .sum()
+    }
+}
+
+fn push_diff_line(diff: &mut Vec<u8>, marker: u8, line: &[u8], terminator: &[u8]) {
+    diff.push(marker);
+    diff.extend_from_slice(line);
+    diff.push(b'\n');
+    if terminator.is_empty() {
+        diff.extend_from_slice(b"\\ No newline at end of file\n");
+    }
+}
+
+// Atomically replaces the contents of `path`: we write to a temporary file next to it, flush it to
+// disk and only then rename it over the original. Either the old or the new contents are there,
+// even if we crash half-way. The temporary file gets a random name that no stale file can be in the
+// way of, and on failure, it is removed again when dropped. If `path` is a symlink, it is the file
+// it points to that we replace, or the rename would turn the link into a regular file.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let path = fs::canonicalize(path)?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+    let permissions = fs::metadata(&path)?.permissions();
+
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(&format!(".{}.", file_name.to_string_lossy()))
+        .suffix(".rgrep.tmp")
+        .tempfile_in(dir)?;
+    temp_file.write_all(contents)?;
+    temp_file.as_file().set_permissions(permissions)?;
+    temp_file.as_file().sync_all()?;
+    temp_file.persist(&path).map_err(|error| error.error)?;
+
+    // To make the rename itself durable, the directory has to be synced as well. Not every
+    // platform lets us open a directory, so this is best-effort.
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+// Applies the replacement to every file of `options`, or, in `dry_run` mode, prints the diff of
+// what would change. Binary files are left alone, unless `options.binary` says to treat them as
+// text. A file that cannot be read or written is reported and left untouched, and we carry on with
+// the others; the error returned at the end says how many files failed. Otherwise, we return
+// whether any file was (or, in `dry_run` mode, would be) changed.
+pub fn rewrite_files(
+    options: &Options,
+    re: &Regex,
+    template: &[u8],
+    dry_run: bool,
+) -> io::Result<bool> {
+    let mut failed = 0;
+    let mut changed = false;
+
+    for file_name in &options.files {
+        let file_name = file_name.as_str();
+        let result = fs::read(file_name).and_then(|contents| {
+            if is_binary(options, &contents) {
+                return Ok(());
+            }
+
+            let rewrite = Rewrite::new(re, template, &contents);
+
+            if !rewrite.is_changed() {
+                return Ok(());
+            }
+
+            changed = true;
+            if dry_run {
+                io::stdout().write_all(&rewrite.unified_diff(file_name))
+            } else {
+                write_atomically(Path::new(file_name), &rewrite.contents())
+            }
+        });
+
+        if let Err(error) = result {
+            eprintln!("rgrep: {file_name}: {error}");
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(io::Error::other(format!(
+            "failed to rewrite {failed} file(s)"
+        )));
+    }
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::{rewrite_files, Rewrite};
+    use part13::regex::bytes::Regex;
+    use part13::{BinaryMode, Options};
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    fn options(files: &[&PathBuf], binary: BinaryMode) -> Options {
+        Options {
+            use_regexp_mode: true,
+            binary,
+            files: files
+                .iter()
+                .map(|path| path.to_str().unwrap().to_string().into())
+                .collect(),
+            ..Options::new([r"let (\w)"])
+        }
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let re = Regex::new("b").unwrap();
+        let contents = b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nb";
+        let rewrite = Rewrite::new(&re, b"B", contents);
+
+        assert_eq!(rewrite.contents(), b"a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nB");
+        assert_eq!(
+            String::from_utf8(rewrite.unified_diff("file")).unwrap(),
+            "--- file\n+++ file\n\
+             @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+             @@ -8,4 +8,4 @@\n h\n i\n j\n-b\n\\ No newline at end of file\n+B\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let changed = dir.join("changed.txt");
+        let untouched = dir.join("untouched.txt");
+        let missing = dir.join("missing.txt");
+        let binary = dir.join("binary.bin");
+        fs::write(&changed, "let a = 1;\r\nlet b = 2;\n").unwrap();
+        fs::write(&untouched, "nothing\n").unwrap();
+        fs::write(&binary, "let z\0").unwrap();
+
+        let re = Regex::new(r"let (\w)").unwrap();
+        let files = [&missing, &changed, &untouched, &binary];
+        let result = rewrite_files(
+            &options(&files, BinaryMode::Detect),
+            &re,
+            b"const $1",
+            false,
+        );
+
+        // The missing file is reported, but does not keep us from rewriting the others. Binary
+        // files are only rewritten when they are to be treated as text.
+        assert!(result.is_err());
+        assert_eq!(
+            fs::read_to_string(&changed).unwrap(),
+            "const a = 1;\r\nconst b = 2;\n"
+        );
+        assert_eq!(fs::read_to_string(&untouched).unwrap(), "nothing\n");
+        assert_eq!(fs::read_to_string(&binary).unwrap(), "let z\0");
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 3);
+
+        let text = options(&[&binary], BinaryMode::Text);
+        assert!(rewrite_files(&text, &re, b"const $1", false).unwrap());
+        assert_eq!(fs::read_to_string(&binary).unwrap(), "const z\0");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_rewrite_symlink() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let target = dir.join("target.txt");
+        let link = dir.join("link.txt");
+        fs::write(&target, "let a = 1;\n").unwrap();
+        symlink("target.txt", &link).unwrap();
+
+        let re = Regex::new(r"let (\w)").unwrap();
+        let options = options(&[&link], BinaryMode::Detect);
+        assert!(rewrite_files(&options, &re, b"const $1", false).unwrap());
+
+        // The link stays a link, and the file it points to gets the new contents.
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "const a = 1;\n");
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+    }
+}
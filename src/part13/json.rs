@@ -0,0 +1,216 @@
+// Scripts should not have to parse `file:number: line`, which is ambiguous as soon as a file name
+// contains a colon. In JSON mode we print one JSON object per line instead: a `begin` and an `end`
+// event around the matches of every file, a `match` event per matching line and a final `summary`.
+// The objects are small and flat, so we write them by hand rather than pulling in a serializer.
+// Lines that are not valid UTF-8 are decoded lossily; byte offsets always refer to the original
+// bytes. With several patterns, every submatch says which of them it matched, by its index in the
+// list of patterns in the summary. A match in a binary file has no text to show, so instead of the
+// line and its submatches, its event says that it is `binary`.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use part13::regex::bytes::Regex;
+use part13::stats::Stats;
+use part13::MatchedLine;
+
+// Turns `s` into a JSON string literal. Besides quotes and backslashes, JSON forbids raw control
+// characters in strings, so those are escaped as well.
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c < ' ' => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+
+    escaped
+}
+
+// Finds the pattern a match belongs to, given the patterns compiled one by one. Like the search, we
+// prefer the first pattern matching exactly there.
+fn pattern_index(regexes: &[Regex], line: &[u8], start: usize, end: usize) -> usize {
+    if regexes.len() == 1 {
+        return 0;
+    }
+
+    regexes
+        .iter()
+        .position(|re| {
+            re.find_at(line, start)
+                .is_some_and(|m| m.start() == start && m.end() == end)
+        })
+        .unwrap_or(0)
+}
+
+fn match_event(matched_line: &MatchedLine, regexes: &[Regex]) -> String {
+    if matched_line.binary {
+        return format!(
+            r#"{{"type":"match","path":{},"line_number":{},"absolute_offset":{},"binary":true}}"#,
+            json_string(&matched_line.file_name),
+            matched_line.number,
+            matched_line.offset,
+        );
+    }
+
+    let submatches: Vec<String> = matched_line
+        .matches
+        .iter()
+        .map(|&(start, end)| {
+            format!(
+                r#"{{"match":{},"start":{start},"end":{end},"pattern":{}}}"#,
+                json_string(&String::from_utf8_lossy(&matched_line.line[start..end])),
+                pattern_index(regexes, &matched_line.line, start, end)
+            )
+        })
+        .collect();
+
+    format!(
+        r#"{{"type":"match","path":{},"line_number":{},"absolute_offset":{},"line":{},"submatches":[{}]}}"#,
+        json_string(&matched_line.file_name),
+        matched_line.number,
+        matched_line.offset,
+        json_string(&String::from_utf8_lossy(&matched_line.line)),
+        submatches.join(",")
+    )
+}
+
+fn end_event(file_name: &str, matched_lines: usize, matches: usize) -> String {
+    format!(
+        r#"{{"type":"end","path":{},"matched_lines":{matched_lines},"matches":{matches}}}"#,
+        json_string(file_name)
+    )
+}
+
+// Writes the events for all `matched_lines` to `out`, where `regexes` are the `patterns` compiled
+// one by one. Since the lines of a file arrive one after the other, a file ends exactly when a line
+// of another file (or nothing) comes in. Files are told apart by their index, not by their name. If
+// there are `stats`, they are included in the summary.
+pub fn print_events(
+    out: &mut impl Write,
+    patterns: &[String],
+    regexes: &[Regex],
+    stats: Option<&Stats>,
+    matched_lines: impl Iterator<Item = MatchedLine>,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut current_file: Option<(usize, Arc<String>)> = None;
+    let (mut file_lines, mut file_matches) = (0, 0);
+    let (mut total_lines, mut total_matches, mut files_with_matches) = (0, 0, 0);
+
+    for matched_line in matched_lines {
+        let new_file = current_file.as_ref().map_or(true, |&(file_index, _)| {
+            file_index != matched_line.file_index
+        });
+        if new_file {
+            if let Some((_, ref file_name)) = current_file {
+                writeln!(out, "{}", end_event(file_name, file_lines, file_matches))?;
+            }
+
+            writeln!(
+                out,
+                r#"{{"type":"begin","path":{}}}"#,
+                json_string(&matched_line.file_name)
+            )?;
+            current_file = Some((matched_line.file_index, matched_line.file_name.clone()));
+            files_with_matches += 1;
+            file_lines = 0;
+            file_matches = 0;
+        }
+
+        writeln!(out, "{}", match_event(&matched_line, regexes))?;
+        file_lines += 1;
+        file_matches += matched_line.matches.len();
+        total_lines += 1;
+        total_matches += matched_line.matches.len();
+    }
+
+    if let Some((_, ref file_name)) = current_file {
+        writeln!(out, "{}", end_event(file_name, file_lines, file_matches))?;
+    }
+
+    let patterns: Vec<String> = patterns
+        .iter()
+        .map(|pattern| json_string(pattern))
+        .collect();
+    let stats = match stats {
+        Some(stats) => format!(r#","stats":{}"#, stats.json()),
+        None => String::new(),
+    };
+    writeln!(
+        out,
+        r#"{{"type":"summary","patterns":[{}],"matched_lines":{total_lines},"matches":{total_matches},"files_with_matches":{files_with_matches},"elapsed_secs":{:.6}{stats}}}"#,
+        patterns.join(","),
+        start.elapsed().as_secs_f64()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, match_event};
+    use part13::regex::bytes::Regex;
+    use part13::MatchedLine;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(
+            json_string("a \"b\" \\ c\td\n\u{1}é"),
+            r#""a \"b\" \\ c\td\n\u0001é""#
+        );
+    }
+
+    #[test]
+    fn test_match_event() {
+        let matched_line = MatchedLine {
+            file_name: "src/a:b.rs".to_string().into(),
+            file_index: 0,
+            number: 7,
+            offset: 120,
+            line: b"let \"x\" = let_y;".to_vec(),
+            matches: vec![(0, 3), (10, 13)],
+            binary: false,
+        };
+
+        let regexes = [Regex::new("let").unwrap()];
+        assert_eq!(
+            match_event(&matched_line, &regexes),
+            r#"{"type":"match","path":"src/a:b.rs","line_number":7,"absolute_offset":120,"line":"let \"x\" = let_y;","submatches":[{"match":"let","start":0,"end":3,"pattern":0},{"match":"let","start":10,"end":13,"pattern":0}]}"#
+        );
+
+        // Of several patterns, the first one matching exactly is reported.
+        let regexes = [
+            Regex::new("x").unwrap(),
+            Regex::new(r"\bl\w+_\w").unwrap(),
+            Regex::new("let").unwrap(),
+        ];
+        let matched_line = MatchedLine {
+            matches: vec![(0, 3), (10, 15)],
+            ..matched_line
+        };
+        assert!(match_event(&matched_line, &regexes).ends_with(
+            r#"[{"match":"let","start":0,"end":3,"pattern":2},{"match":"let_y","start":10,"end":15,"pattern":1}]}"#
+        ));
+
+        // Of a binary file, we only say where the match is.
+        let matched_line = MatchedLine {
+            binary: true,
+            ..matched_line
+        };
+        assert_eq!(
+            match_event(&matched_line, &regexes),
+            r#"{"type":"match","path":"src/a:b.rs","line_number":7,"absolute_offset":120,"binary":true}"#
+        );
+    }
+}
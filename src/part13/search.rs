@@ -0,0 +1,780 @@
+// Searching works on raw bytes rather than on `String`s: files do not have to be valid UTF-8 (think
+// of Latin-1 logs), and we do not want to pay for decoding lines that do not even match. Instead of
+// looking at one line after the other, we search a whole buffer of lines at once and only find the
+// line around a match afterwards. Lines are decoded only when they are printed.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+extern crate aho_corasick;
+extern crate memchr;
+extern crate memmap2;
+
+use self::aho_corasick::{AhoCorasick, Input, MatchKind};
+use self::memchr::{memchr, memchr_iter, memmem, memrchr};
+use self::memmap2::Mmap;
+
+use part13::decompress::decompressed;
+use part13::regex::bytes::Regex;
+use part13::{invalid_pattern, pattern_regex, BinaryMode, Options, OutputMode};
+
+// Files smaller than this are not worth setting up a memory map for.
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+// Files that are not memory-mapped are read in chunks of (at least) this size.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+// How much of the beginning of a file we look at to decide whether it is binary.
+const BINARY_PROBE: usize = 8 * 1024;
+
+// The patterns, compiled once according to the mode. A single literal pattern is searched with
+// `memmem`, which is a lot faster than going through the regex engine, and several of them with an
+// Aho-Corasick automaton, which looks for all of them in a single pass. Both scan whole buffers
+// with SIMD instructions where the CPU has them. The finder is much larger than the others, so it
+// lives on the heap.
+//
+// Ignoring case, the automaton folds ASCII letters for us, which covers almost all patterns: it
+// only misses the few letters outside of ASCII that fold to ASCII ones, like the Kelvin sign. Other
+// letters may have case variants of different lengths in UTF-8, like "ſ" and "S", which only the
+// regex engine handles, so literal patterns that are not ASCII are escaped and searched as regular
+// expressions, with Unicode case folding.
+pub enum Matcher {
+    Regex(Regex),
+    Literal(Box<memmem::Finder<'static>>),
+    Literals(AhoCorasick),
+}
+
+impl Matcher {
+    pub fn new(options: &Options) -> io::Result<Self> {
+        let ascii = options.patterns.iter().all(|pattern| pattern.is_ascii());
+
+        if options.use_regexp_mode || (options.ignore_case && !ascii) {
+            Ok(Matcher::Regex(pattern_regex(options)?))
+        } else if let (false, [ref pattern]) = (options.ignore_case, &options.patterns[..]) {
+            Ok(Matcher::Literal(Box::new(
+                memmem::Finder::new(pattern.as_bytes()).into_owned(),
+            )))
+        } else {
+            // Like with regular expressions, the first of several patterns matching at the same
+            // position wins. Building only fails for absurdly many or long patterns.
+            let automaton = AhoCorasick::builder()
+                .match_kind(MatchKind::LeftmostFirst)
+                .ascii_case_insensitive(options.ignore_case)
+                .build(&options.patterns)
+                .map_err(invalid_pattern)?;
+
+            Ok(Matcher::Literals(automaton))
+        }
+    }
+
+    // Finds the first match in `haystack` that starts at `start` or later, and returns its range.
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        match *self {
+            Matcher::Regex(ref re) => re.find_at(haystack, start).map(|m| (m.start(), m.end())),
+            Matcher::Literal(ref finder) => finder
+                .find(&haystack[start..])
+                .map(|i| (start + i, start + i + finder.needle().len())),
+            Matcher::Literals(ref automaton) => automaton
+                .find(Input::new(haystack).span(start..haystack.len()))
+                .map(|m| (m.start(), m.end())),
+        }
+    }
+
+    // Returns the byte ranges of all matches in a single `line`.
+    pub fn find_all(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        match *self {
+            Matcher::Regex(ref re) => re.find_iter(line).map(|m| (m.start(), m.end())).collect(),
+            Matcher::Literal(ref finder) => {
+                let length = finder.needle().len();
+
+                finder.find_iter(line).map(|i| (i, i + length)).collect()
+            }
+            Matcher::Literals(ref automaton) => automaton
+                .find_iter(line)
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+        }
+    }
+}
+
+// What we tell the caller about a matching line. Lines are numbered from 1, and `offset` is where
+// the line starts in the file. `binary` is set for lines from binary files.
+pub struct FoundLine<'a> {
+    pub number: usize,
+    pub offset: usize,
+    pub line: &'a [u8],
+    pub matches: Vec<(usize, usize)>,
+    pub binary: bool,
+}
+
+// Whoever searches a file gets the matching lines one by one, and returns `false` from `found` to
+// stop the search. Between the chunks of a file, `keep_reading` is asked whether the rest is still
+// needed, as there may be no more matches to say so. When the file has nothing more to give for
+// now, like a pipe whose writer is slow, `flush` is told to pass on what was found so far. Any
+// closure taking the lines will do as well.
+pub trait Found {
+    fn found(&mut self, found_line: FoundLine) -> bool;
+
+    fn keep_reading(&mut self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) {}
+}
+
+impl<F: FnMut(FoundLine) -> bool> Found for F {
+    fn found(&mut self, found_line: FoundLine) -> bool {
+        self(found_line)
+    }
+}
+
+// Marks the lines from a binary file, and stops at the first one if `stop_early` is set.
+fn mark_binary<'a>(
+    found: &'a mut impl Found,
+    binary: bool,
+    stop_early: bool,
+) -> impl FnMut(FoundLine) -> bool + 'a {
+    move |mut found_line: FoundLine| {
+        found_line.binary = binary;
+        found.found(found_line) && !stop_early
+    }
+}
+
+// Searches `buffer`, which has to consist of complete lines, and calls `found` for every matching
+// line. `first_line` is the number of the first line in the buffer, and `first_offset` the position
+// of the buffer in the file. Returns the number of line terminators in the buffer, or `None` if
+// `found` returned `false` to stop the search.
+//
+// A match in the buffer only tells us which line to look at: a regular expression like `a\s+b` may
+// match across lines, so we run the matcher on the line itself once more. If that does not match,
+// we simply continue with the next line.
+pub fn search_buffer(
+    matcher: &Matcher,
+    buffer: &[u8],
+    first_line: usize,
+    first_offset: usize,
+    found: &mut impl FnMut(FoundLine) -> bool,
+) -> Option<usize> {
+    let mut position = 0;
+    let mut line_number = first_line;
+    let mut counted_up_to = 0;
+
+    while position < buffer.len() {
+        let Some((start, _)) = matcher.find_at(buffer, position) else {
+            break;
+        };
+        let line_start =
+            memrchr(b'\n', &buffer[position..start]).map_or(position, |i| position + i + 1);
+        let line_end = memchr(b'\n', &buffer[start..]).map_or(buffer.len(), |i| start + i);
+
+        // Line numbers are only computed for lines that are interesting, by counting the
+        // terminators we skipped since the last one.
+        line_number += memchr_iter(b'\n', &buffer[counted_up_to..line_start]).count();
+        counted_up_to = line_start;
+
+        // Like `lines` does, we strip the line ending, be it "\n" or "\r\n".
+        let mut line = &buffer[line_start..line_end];
+        if line_end < buffer.len() && line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+
+        let matches = matcher.find_all(line);
+        if !matches.is_empty() {
+            let found_line = FoundLine {
+                number: line_number,
+                offset: first_offset + line_start,
+                line,
+                matches,
+                binary: false,
+            };
+
+            if !found(found_line) {
+                return None;
+            }
+        }
+
+        position = line_end + 1;
+    }
+
+    Some(line_number - first_line + memchr_iter(b'\n', &buffer[counted_up_to..]).count())
+}
+
+// The range of some lines and the matches in them.
+type PendingLines = (usize, usize, Vec<(usize, usize)>);
+
+// In multiline mode, a match may span several lines, so we search the whole contents of a file at
+// once and report every match with all the lines it touches, at the number of its first line.
+// Matches that share a line are reported together. Returns `false` if `found` stopped the search.
+pub fn search_multiline(
+    matcher: &Matcher,
+    contents: &[u8],
+    found: &mut impl FnMut(FoundLine) -> bool,
+) -> bool {
+    let mut position = 0;
+    let mut line_number = 1;
+    let mut counted_up_to = 0;
+    // The lines of the matches we have not reported yet, and the matches themselves.
+    let mut pending: Option<PendingLines> = None;
+    let mut report = |(lines_start, lines_end, matches): PendingLines| {
+        line_number += memchr_iter(b'\n', &contents[counted_up_to..lines_start]).count();
+        counted_up_to = lines_start;
+
+        let mut lines = &contents[lines_start..lines_end];
+        if lines_end < contents.len() && lines.last() == Some(&b'\r') {
+            lines = &lines[..lines.len() - 1];
+        }
+        // A match may include the terminator of its last line, which is not part of `lines`.
+        let matches = matches
+            .iter()
+            .map(|&(start, end)| (start - lines_start, (end - lines_start).min(lines.len())))
+            .collect();
+
+        found(FoundLine {
+            number: line_number,
+            offset: lines_start,
+            line: lines,
+            matches,
+            binary: false,
+        })
+    };
+
+    while position <= contents.len() {
+        let Some((start, end)) = matcher.find_at(contents, position) else {
+            break;
+        };
+        // After the terminator of the last line, or in an empty file, there is no line left for an
+        // empty match to be in.
+        if start == contents.len() && contents.last().map_or(true, |&byte| byte == b'\n') {
+            break;
+        }
+        let lines_start = memrchr(b'\n', &contents[..start]).map_or(0, |i| i + 1);
+        // A match ending in a line terminator does not touch the line after it.
+        let last = if end > start && contents[end - 1] == b'\n' {
+            end - 1
+        } else {
+            end
+        };
+        let lines_end = memchr(b'\n', &contents[last..]).map_or(contents.len(), |i| last + i);
+
+        match pending {
+            Some((_, ref mut pending_end, ref mut matches)) if lines_start <= *pending_end => {
+                *pending_end = lines_end.max(*pending_end);
+                matches.push((start, end));
+            }
+            _ => {
+                if let Some(lines) = pending.take() {
+                    if !report(lines) {
+                        return false;
+                    }
+                }
+                pending = Some((lines_start, lines_end, vec![(start, end)]));
+            }
+        }
+
+        // An empty match would be found again and again, so we skip ahead.
+        position = if end > start { end } else { end + 1 };
+    }
+
+    pending.is_none_or(report)
+}
+
+// How much of a file we read, and how long that took. Compressed files are counted after
+// decompression, as that is what we search. The last line counts even without a terminator.
+#[derive(Default)]
+pub struct FileStats {
+    pub bytes: u64,
+    pub read_time: Duration,
+    terminators: u64,
+    // Whether the last byte we counted was in the middle of a line.
+    open_line: bool,
+}
+
+impl FileStats {
+    fn count(&mut self, data: &[u8]) {
+        if let Some(&last) = data.last() {
+            self.bytes += data.len() as u64;
+            self.terminators += memchr_iter(b'\n', data).count() as u64;
+            self.open_line = last != b'\n';
+        }
+    }
+
+    pub fn lines(&self) -> u64 {
+        self.terminators + self.open_line as u64
+    }
+}
+
+// A file counts as binary if there is a NUL byte in its first block. Text files practically never
+// contain one, while most binary formats do.
+pub fn is_binary(options: &Options, first_block: &[u8]) -> bool {
+    let probe = &first_block[..first_block.len().min(BINARY_PROBE)];
+
+    options.binary != BinaryMode::Text && memchr(0, probe).is_some()
+}
+
+// Applies `options.binary` to a file with the given first block. Returns `None` if the file is to
+// be skipped, and otherwise whether the file is binary and whether the search can stop after the
+// first match: binary files are only reported as a whole, unless we are counting.
+fn binary_policy(options: &Options, first_block: &[u8]) -> Option<(bool, bool)> {
+    let binary = is_binary(options, first_block);
+
+    if binary && options.binary == BinaryMode::Skip {
+        return None;
+    }
+
+    let count_only = matches!(
+        options.output_mode,
+        OutputMode::Count | OutputMode::CountPerFile
+    );
+
+    Some((binary, binary && !count_only))
+}
+
+// `read` may be interrupted by a signal before it read anything, in which case we just try again.
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buffer) {
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+// Reads like `read_chunk`, and counts what we read in `file_stats`.
+fn read_counted(
+    reader: &mut impl Read,
+    buffer: &mut [u8],
+    file_stats: &mut FileStats,
+) -> io::Result<usize> {
+    let started = Instant::now();
+    let read = read_chunk(reader, buffer)?;
+
+    file_stats.read_time += started.elapsed();
+    file_stats.count(&buffer[..read]);
+    Ok(read)
+}
+
+// Searches a whole file, where "-" stands for standard input. With `options.search_zip`, compressed
+// input is decompressed first. Large regular files may be memory-mapped if `options.mmap` is set;
+// everything else is read in chunks into `buffer`, which the caller can reuse for the next file.
+// What was read is counted in `file_stats`.
+pub fn search_file(
+    options: &Options,
+    matcher: &Matcher,
+    file_name: &str,
+    buffer: &mut Vec<u8>,
+    file_stats: &mut FileStats,
+    found: &mut impl Found,
+) -> io::Result<()> {
+    if file_name == "-" {
+        let stdin = io::stdin().lock();
+
+        return if options.search_zip {
+            let mut reader = decompressed(stdin)?;
+            search_reader(options, matcher, &mut reader, buffer, file_stats, found)
+        } else {
+            search_reader(options, matcher, &mut { stdin }, buffer, file_stats, found)
+        };
+    }
+
+    let mut file = File::open(file_name)?;
+    let metadata = file.metadata()?;
+
+    // Compressed files have to be read through the decompressor, so there is no point in mapping
+    // them.
+    if options.search_zip {
+        let mut reader = decompressed(file)?;
+        return search_reader(options, matcher, &mut reader, buffer, file_stats, found);
+    }
+
+    if options.mmap && metadata.is_file() && metadata.len() >= MMAP_THRESHOLD {
+        // Mapping a file is `unsafe`, because the contents of the mapping change (or even vanish)
+        // if some other process modifies the file while we are looking at it. That's a risk the
+        // user explicitly accepts by asking for memory maps.
+        let map = unsafe { Mmap::map(&file)? };
+
+        file_stats.count(&map);
+        search_contents(options, matcher, map.deref(), found);
+        return Ok(());
+    }
+
+    search_reader(options, matcher, &mut file, buffer, file_stats, found)
+}
+
+// Searches the complete `contents` of a file at once.
+fn search_contents(options: &Options, matcher: &Matcher, contents: &[u8], found: &mut impl Found) {
+    let Some((binary, stop_early)) = binary_policy(options, contents) else {
+        return;
+    };
+    let mut found = mark_binary(found, binary, stop_early);
+
+    if options.multiline {
+        search_multiline(matcher, contents, &mut found);
+    } else {
+        search_buffer(matcher, contents, 1, 0, &mut found);
+    }
+}
+
+// Searches everything `reader` gives us, chunk by chunk. In multiline mode, a match may span any
+// number of lines, so we have to read everything before we can search it.
+fn search_reader(
+    options: &Options,
+    matcher: &Matcher,
+    reader: &mut impl Read,
+    buffer: &mut Vec<u8>,
+    file_stats: &mut FileStats,
+    found: &mut impl Found,
+) -> io::Result<()> {
+    if options.multiline {
+        let started = Instant::now();
+        buffer.clear();
+        reader.read_to_end(buffer)?;
+        file_stats.read_time += started.elapsed();
+        file_stats.count(buffer);
+        search_contents(options, matcher, buffer, found);
+        return Ok(());
+    }
+
+    if buffer.len() < CHUNK_SIZE {
+        buffer.resize(CHUNK_SIZE, 0);
+    }
+
+    let mut filled = read_counted(reader, buffer, file_stats)?;
+    let mut at_end = filled == 0;
+    // A read that did not fill the space we offered means that there is nothing more right now.
+    let mut short_read = filled < buffer.len();
+    let mut line_number = 1;
+    // Where the beginning of `buffer` is in the file.
+    let mut offset = 0;
+    let Some((binary, stop_early)) = binary_policy(options, &buffer[..filled]) else {
+        return Ok(());
+    };
+
+    loop {
+        // At the end of the file, whatever is left is the last line.
+        if at_end {
+            let mut found = mark_binary(found, binary, stop_early);
+            search_buffer(matcher, &buffer[..filled], line_number, offset, &mut found);
+            return Ok(());
+        }
+
+        // We search all complete lines we have, and move the beginning of the incomplete last
+        // line to the front of the buffer for the next round.
+        if let Some(last_terminator) = memrchr(b'\n', &buffer[..filled]) {
+            let complete = last_terminator + 1;
+
+            match search_buffer(
+                matcher,
+                &buffer[..complete],
+                line_number,
+                offset,
+                &mut mark_binary(found, binary, stop_early),
+            ) {
+                Some(lines) => line_number += lines,
+                None => return Ok(()),
+            }
+            offset += complete;
+            buffer.copy_within(complete..filled, 0);
+            filled -= complete;
+        }
+
+        if short_read {
+            found.flush();
+        }
+
+        // If a single line does not fit into the buffer, we make it larger.
+        if filled == buffer.len() {
+            buffer.resize(2 * buffer.len(), 0);
+        }
+
+        if !found.keep_reading() {
+            return Ok(());
+        }
+
+        let read = read_counted(reader, &mut buffer[filled..], file_stats)?;
+        at_end = read == 0;
+        short_read = filled + read < buffer.len();
+        filled += read;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        binary_policy, search_buffer, search_multiline, search_reader, FileStats, FoundLine,
+        Matcher,
+    };
+    use part13::{BinaryMode, Options, OutputMode};
+    use std::io::{self, Read};
+    use std::time::Instant;
+
+    fn options(patterns: &[&str], use_regexp_mode: bool) -> Options {
+        Options {
+            use_regexp_mode,
+            threads: 1,
+            ..Options::new(patterns.iter().copied())
+        }
+    }
+
+    fn search(patterns: &[&str], use_regexp_mode: bool, buffer: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        let matcher = Matcher::new(&options(patterns, use_regexp_mode)).unwrap();
+        let mut found = Vec::new();
+
+        search_buffer(&matcher, buffer, 0, 0, &mut |found_line: FoundLine| {
+            found.push((found_line.number, found_line.line.to_vec()));
+            true
+        });
+
+        found
+    }
+
+    #[test]
+    fn test_search_buffer_literal() {
+        // Latin-1 encoded "é" is not valid UTF-8, but that does not bother us.
+        let buffer = b"caf\xe9 au lait\r\nno match\nlait\n\nlast lait";
+
+        assert_eq!(
+            search(&["lait"], false, buffer),
+            vec![
+                (0, b"caf\xe9 au lait".to_vec()),
+                (2, b"lait".to_vec()),
+                (4, b"last lait".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_buffer_patterns() {
+        let buffer = b"E0308 mismatched types\nwarning\nE0599 no method\nE0308 again";
+
+        for use_regexp_mode in [false, true] {
+            let matcher = Matcher::new(&options(&["E0599", "E0308"], use_regexp_mode)).unwrap();
+            assert_eq!(matcher.find_all(b"E0599, E0308"), vec![(0, 5), (7, 12)]);
+            assert_eq!(
+                search(&["E0599", "E0308"], use_regexp_mode, buffer)
+                    .iter()
+                    .map(|&(number, _)| number)
+                    .collect::<Vec<_>>(),
+                vec![0, 2, 3]
+            );
+        }
+
+        assert_eq!(
+            search(&[r"^warn", r"\d{4} no"], true, buffer),
+            vec![(1, b"warning".to_vec()), (2, b"E0599 no method".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_search_multiline() {
+        let contents = b"fn a(\r\n    x: u8,\n) {}\nfn b(y: u8) {}\nfn c(\n";
+        let matcher = Matcher::new(&options(&[r"\(\s*\n\s*x", r"\{\}", r"c\(\n"], true)).unwrap();
+        let mut found = Vec::new();
+
+        // The first match spans two lines, and the last one includes the terminator of its line.
+        search_multiline(&matcher, contents, &mut |found_line: FoundLine| {
+            found.push((
+                found_line.number,
+                found_line.line.to_vec(),
+                found_line.matches,
+            ));
+            true
+        });
+
+        assert_eq!(
+            found,
+            vec![
+                (1, b"fn a(\r\n    x: u8,".to_vec(), vec![(4, 12)]),
+                (3, b") {}".to_vec(), vec![(2, 4)]),
+                (4, b"fn b(y: u8) {}".to_vec(), vec![(12, 14)]),
+                (5, b"fn c(".to_vec(), vec![(3, 5)]),
+            ]
+        );
+
+        // Empty matches are found in every line, but not after the last one.
+        let matcher = Matcher::new(&options(&["x*"], true)).unwrap();
+        let mut numbers = Vec::new();
+        search_multiline(&matcher, b"a\nb\n", &mut |found_line: FoundLine| {
+            numbers.push(found_line.number);
+            true
+        });
+        assert_eq!(numbers, [1, 2]);
+
+        // An empty file has no lines at all.
+        assert!(search_multiline(&matcher, b"", &mut |_: FoundLine| panic!(
+            "found a line"
+        )));
+    }
+
+    #[test]
+    fn test_search_buffer_regex() {
+        let buffer = b"fn a(\r\nb)\nfn main(x) {\n";
+
+        // Anchors match at every line, also before "\r\n".
+        assert_eq!(
+            search(&[r"\($"], true, buffer),
+            vec![(0, b"fn a(".to_vec())]
+        );
+        assert_eq!(search(&[r"^fn"], true, buffer).len(), 2);
+        // A match across lines does not count, but does not hide later matches either.
+        assert_eq!(
+            search(&[r"\(\s*\w"], true, buffer),
+            vec![(2, b"fn main(x) {".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_matcher_ignore_case() {
+        let matcher = |patterns: &[&str], use_regexp_mode| {
+            let mut options = options(patterns, use_regexp_mode);
+            options.ignore_case = true;
+            Matcher::new(&options).unwrap()
+        };
+
+        assert_eq!(
+            matcher(&["LeT"], false).find_all(b"let LET"),
+            [(0, 3), (4, 7)]
+        );
+        assert_eq!(
+            matcher(&["x", "Y"], false).find_all(b"X y"),
+            [(0, 1), (2, 3)]
+        );
+        assert_eq!(
+            matcher(&[r"\bL\w"], true).find_all(b"la lb"),
+            [(0, 2), (3, 5)]
+        );
+        // Outside of ASCII, the regex engine folds the case, even where the lengths differ.
+        assert_eq!(
+            matcher(&["Été"], false).find_all("été ÉTÉ".as_bytes()),
+            [(0, 5), (6, 11)]
+        );
+        assert_eq!(matcher(&["ſ."], false).find_all(b"s. S?"), [(0, 2)]);
+    }
+
+    #[test]
+    fn test_matcher_invalid_pattern() {
+        let error = Matcher::new(&options(&["(unclosed"], true)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // Literal patterns are never invalid, whatever they contain.
+        assert!(Matcher::new(&options(&["(unclosed"], false)).is_ok());
+    }
+
+    #[test]
+    fn test_binary_policy() {
+        let mut options = options(&["x"], false);
+        let binary = b"\x7fELF\x02\x01\x01\x00\x00";
+
+        assert_eq!(
+            binary_policy(&options, b"plain text\n"),
+            Some((false, false))
+        );
+        assert_eq!(binary_policy(&options, binary), Some((true, true)));
+
+        options.output_mode = OutputMode::Count;
+        assert_eq!(binary_policy(&options, binary), Some((true, false)));
+
+        options.binary = BinaryMode::Skip;
+        assert_eq!(binary_policy(&options, binary), None);
+
+        options.binary = BinaryMode::Text;
+        assert_eq!(binary_policy(&options, binary), Some((false, false)));
+    }
+
+    // Pipes hand out their data in small pieces, which must not confuse the line numbers.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let length = self.0.len().min(buffer.len()).min(3);
+
+            buffer[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn test_search_reader() {
+        let options = options(&["needle"], false);
+        let matcher = Matcher::new(&options).unwrap();
+        let mut found = Vec::new();
+        let mut file_stats = FileStats::default();
+
+        search_reader(
+            &options,
+            &matcher,
+            &mut Trickle(b"hay\nneedle one\nhay\nhay\nlast needle"),
+            &mut Vec::new(),
+            &mut file_stats,
+            &mut |found_line: FoundLine| {
+                found.push((
+                    found_line.number,
+                    found_line.offset,
+                    found_line.line.to_vec(),
+                ));
+                true
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            found,
+            vec![
+                (2, 4, b"needle one".to_vec()),
+                (5, 23, b"last needle".to_vec())
+            ]
+        );
+        // The last line has no terminator, but still counts.
+        assert_eq!((file_stats.bytes, file_stats.lines()), (34, 5));
+    }
+
+    // Compares searching whole buffers with the matcher to what rgrep did before: decoding every
+    // line and calling `str::contains` on it, on a lowercased copy to ignore case. Run it with
+    // `cargo test --release -- --ignored --nocapture bench_literal`.
+    #[test]
+    #[ignore]
+    fn bench_literal() {
+        let text: String = (0..500_000)
+            .map(|n| format!("line {n}: the quick brown fox jumps over the lazy dog\n"))
+            .collect();
+
+        for ignore_case in [false, true] {
+            let start = Instant::now();
+            let pattern = if ignore_case {
+                "LINE 4999"
+            } else {
+                "line 4999"
+            };
+            let lowercase = pattern.to_lowercase();
+            let by_line = text
+                .lines()
+                .filter(|line| {
+                    if ignore_case {
+                        line.to_lowercase().contains(&lowercase)
+                    } else {
+                        line.contains(pattern)
+                    }
+                })
+                .count();
+            let by_line_time = start.elapsed();
+
+            let start = Instant::now();
+            let mut options = options(&[pattern], false);
+            options.ignore_case = ignore_case;
+            let matcher = Matcher::new(&options).unwrap();
+            let mut by_buffer = 0;
+            search_buffer(&matcher, text.as_bytes(), 1, 0, &mut |_: FoundLine| {
+                by_buffer += 1;
+                true
+            });
+            let by_buffer_time = start.elapsed();
+
+            assert_eq!(by_line, by_buffer);
+            println!(
+                "ignore case: {ignore_case}, line by line: {by_line_time:?}, whole buffer: {by_buffer_time:?}"
+            );
+        }
+    }
+}
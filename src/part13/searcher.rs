@@ -0,0 +1,249 @@
+// The command line is not the only way to search: other tools may want the matching lines
+// themselves rather than text on stdout. A `Searcher` is set up like `grep` with a few builder
+// methods, and then either hands out the `MatchedLine`s as an iterator, or passes them to a `Sink`.
+// Printing them like rgrep does is just one such sink.
+
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use part13::{format_matched_lines, BinaryMode, LineFormat, MatchedLine, Matches, Options};
+
+// Receives the matching lines of a search, one after the other. Returning `false` from `matched`
+// stops the search early. A file that cannot be searched is passed to `error` instead, which fails
+// the whole search unless the sink decides to go on. `finish` is called once after the last line.
+pub trait Sink {
+    fn matched(&mut self, matched_line: MatchedLine) -> io::Result<bool>;
+
+    fn error(&mut self, error: io::Error) -> io::Result<bool> {
+        Err(error)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes every line as `file:number: line`, or whatever else `format` asks for, highlighting the
+// matches if `color` is set.
+pub struct Printer<W: Write> {
+    writer: W,
+    format: LineFormat,
+    color: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(writer: W, format: LineFormat, color: bool) -> Self {
+        Printer {
+            writer,
+            format,
+            color,
+        }
+    }
+}
+
+impl<W: Write> Sink for Printer<W> {
+    fn matched(&mut self, matched_line: MatchedLine) -> io::Result<bool> {
+        for line in format_matched_lines(&matched_line, self.format, self.color) {
+            writeln!(self.writer, "{line}")?;
+        }
+        Ok(true)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Any closure taking the lines is a sink as well.
+impl<F: FnMut(MatchedLine) -> bool> Sink for F {
+    fn matched(&mut self, matched_line: MatchedLine) -> io::Result<bool> {
+        Ok(self(matched_line))
+    }
+}
+
+// Builds the `Options` of a search. By default, the patterns are literal, binary files are only
+// reported as a whole, and there are as many threads as the machine has cores.
+pub struct Searcher {
+    options: Options,
+}
+
+impl Searcher {
+    pub fn new<S: Into<String>>(patterns: impl IntoIterator<Item = S>) -> Self {
+        Searcher {
+            options: Options::new(patterns),
+        }
+    }
+
+    // Adds a file to search, where "-" stands for standard input. Directories have to be expanded
+    // with `discover_files` first.
+    pub fn file(mut self, file_name: impl Into<String>) -> Self {
+        self.options.files.push(Arc::new(file_name.into()));
+        self
+    }
+
+    pub fn files(mut self, files: Vec<Arc<String>>) -> Self {
+        self.options.files.extend(files);
+        self
+    }
+
+    pub fn regexp(mut self, use_regexp_mode: bool) -> Self {
+        self.options.use_regexp_mode = use_regexp_mode;
+        self
+    }
+
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.options.ignore_case = ignore_case;
+        self
+    }
+
+    pub fn multiline(mut self, multiline: bool) -> Self {
+        self.options.multiline = multiline;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.options.mmap = mmap;
+        self
+    }
+
+    pub fn search_zip(mut self, search_zip: bool) -> Self {
+        self.options.search_zip = search_zip;
+        self
+    }
+
+    pub fn binary(mut self, binary: BinaryMode) -> Self {
+        self.options.binary = binary;
+        self
+    }
+
+    pub fn max_count(mut self, max_count: usize) -> Self {
+        self.options.max_count = Some(max_count);
+        self
+    }
+
+    pub fn max_total(mut self, max_total: usize) -> Self {
+        self.options.max_total = Some(max_total);
+        self
+    }
+
+    // Starts the search in the background, and returns the matching lines as they come in. Fails
+    // if a pattern is not a valid regular expression.
+    pub fn search(self) -> io::Result<Matches> {
+        Matches::start(Arc::new(self.options))
+    }
+
+    // Searches, and passes every matching line to `sink`. Returns whether anything matched.
+    pub fn search_with(self, sink: &mut impl Sink) -> io::Result<bool> {
+        let mut matched = false;
+
+        for matched_line in self.search()? {
+            let go_on = match matched_line {
+                Ok(matched_line) => {
+                    matched = true;
+                    sink.matched(matched_line)?
+                }
+                Err(error) => sink.error(error)?,
+            };
+            if !go_on {
+                break;
+            }
+        }
+
+        sink.finish()?;
+        Ok(matched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::{Printer, Searcher};
+    use part13::{LineFormat, MatchedLine};
+    use std::{fs, io};
+
+    #[test]
+    fn test_searcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = [("a.txt", "let a;\nconst b;\n"), ("b.txt", "let c = d;\n")]
+            .iter()
+            .map(|(name, contents)| {
+                let path = dir.path().join(name);
+                fs::write(&path, contents).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+        let searcher = || {
+            Searcher::new(vec![r"let \w", "d"])
+                .regexp(true)
+                .threads(2)
+                .file(files[0].clone())
+                .file(files[1].clone())
+        };
+
+        let lines: Vec<(usize, Vec<(usize, usize)>)> = searcher()
+            .search()
+            .unwrap()
+            .map(|matched_line| {
+                let matched_line = matched_line.unwrap();
+                (matched_line.number, matched_line.matches)
+            })
+            .collect();
+        assert_eq!(lines, [(1, vec![(0, 5)]), (1, vec![(0, 5), (8, 9)])]);
+
+        let mut printed = Vec::new();
+        assert!(searcher()
+            .search_with(&mut Printer::new(
+                &mut printed,
+                LineFormat::default(),
+                false
+            ))
+            .unwrap());
+        assert_eq!(
+            String::from_utf8(printed).unwrap(),
+            format!("{}:1: let a;\n{}:1: let c = d;\n", files[0], files[1])
+        );
+
+        // A closure stopping at the first line stops the search.
+        let mut first = Vec::new();
+        let mut sink = |matched_line: MatchedLine| {
+            first.push(matched_line.file_name);
+            false
+        };
+        assert!(searcher().search_with(&mut sink).unwrap());
+        assert_eq!(first.len(), 1);
+
+        // Not a single line is wanted, from any file.
+        assert_eq!(searcher().max_count(0).search().unwrap().count(), 0);
+
+        assert!(!Searcher::new(vec!["nowhere"])
+            .file(files[0].clone())
+            .search_with(&mut |_| true)
+            .unwrap());
+
+        // A missing file is an error in its place, and the other files are still searched.
+        let missing = dir.path().join("missing.txt").to_str().unwrap().to_string();
+        let results: Vec<_> = Searcher::new(vec!["let"])
+            .file(missing.clone())
+            .file(files[1].clone())
+            .search()
+            .unwrap()
+            .collect();
+        assert_eq!(results.len(), 2);
+        let error = results[0].as_ref().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().starts_with(&missing));
+        assert_eq!(results[1].as_ref().unwrap().number, 1);
+
+        // Unless the sink says otherwise, an error fails the search.
+        assert!(Searcher::new(vec!["let"])
+            .file(missing)
+            .search_with(&mut |_| true)
+            .is_err());
+    }
+}
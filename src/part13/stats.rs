@@ -0,0 +1,147 @@
+// With `--stats`, rgrep reports how much work a search took. The workers add up, file by file, what
+// they read and found, and how long reading and searching took them. The output thread remembers
+// how long it waited for lines; the rest of its time was spent on the output. Reading and searching
+// are summed over all workers, so with several threads they may well add up to more than the total.
+// The statistics are taken once the output is done.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use part13::search::FileStats;
+
+pub struct Stats {
+    start: Instant,
+    files: AtomicU64,
+    bytes: AtomicU64,
+    lines: AtomicU64,
+    matching_lines: AtomicU64,
+    matches: AtomicU64,
+    // Times are kept in nanoseconds, as there are no atomic `Duration`s.
+    read_nanos: AtomicU64,
+    filter_nanos: AtomicU64,
+    wait_nanos: AtomicU64,
+    // When the output thread started, counted from `start`.
+    output_nanos: AtomicU64,
+}
+
+fn add_time(counter: &AtomicU64, time: Duration) {
+    counter.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+}
+
+fn seconds(counter: &AtomicU64) -> f64 {
+    Duration::from_nanos(counter.load(Ordering::Relaxed)).as_secs_f64()
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            start: Instant::now(),
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            lines: AtomicU64::new(0),
+            matching_lines: AtomicU64::new(0),
+            matches: AtomicU64::new(0),
+            read_nanos: AtomicU64::new(0),
+            filter_nanos: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+            output_nanos: AtomicU64::new(0),
+        }
+    }
+
+    // Adds a searched file, which took `elapsed` to read and search altogether.
+    pub fn add_file(
+        &self,
+        file_stats: &FileStats,
+        matching_lines: usize,
+        matches: usize,
+        elapsed: Duration,
+    ) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(file_stats.bytes, Ordering::Relaxed);
+        self.lines.fetch_add(file_stats.lines(), Ordering::Relaxed);
+        self.matching_lines
+            .fetch_add(matching_lines as u64, Ordering::Relaxed);
+        self.matches.fetch_add(matches as u64, Ordering::Relaxed);
+        add_time(&self.read_nanos, file_stats.read_time);
+        add_time(
+            &self.filter_nanos,
+            elapsed.saturating_sub(file_stats.read_time),
+        );
+    }
+
+    pub fn start_output(&self) {
+        add_time(&self.output_nanos, self.start.elapsed());
+    }
+
+    pub fn add_wait(&self, waited: Duration) {
+        add_time(&self.wait_nanos, waited);
+    }
+
+    // Whenever the output thread was not waiting for lines, it was busy.
+    fn output_seconds(&self) -> f64 {
+        let running = self.start.elapsed().as_secs_f64() - seconds(&self.output_nanos);
+
+        (running - seconds(&self.wait_nanos)).max(0.0)
+    }
+
+    pub fn print(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out)?;
+        writeln!(out, "{} files searched", self.files.load(Ordering::Relaxed))?;
+        writeln!(out, "{} bytes read", self.bytes.load(Ordering::Relaxed))?;
+        writeln!(out, "{} lines scanned", self.lines.load(Ordering::Relaxed))?;
+        writeln!(
+            out,
+            "{} matching lines",
+            self.matching_lines.load(Ordering::Relaxed)
+        )?;
+        writeln!(out, "{} matches", self.matches.load(Ordering::Relaxed))?;
+        writeln!(out, "{:.6} seconds reading", seconds(&self.read_nanos))?;
+        writeln!(out, "{:.6} seconds filtering", seconds(&self.filter_nanos))?;
+        writeln!(out, "{:.6} seconds printing", self.output_seconds())?;
+        writeln!(
+            out,
+            "{:.6} seconds total",
+            self.start.elapsed().as_secs_f64()
+        )
+    }
+
+    // The same as a JSON object, to be embedded into the summary.
+    pub fn json(&self) -> String {
+        format!(
+            r#"{{"files_searched":{},"bytes_read":{},"lines_scanned":{},"matching_lines":{},"matches":{},"elapsed_secs":{{"reader":{:.6},"filter":{:.6},"output":{:.6},"total":{:.6}}}}}"#,
+            self.files.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+            self.lines.load(Ordering::Relaxed),
+            self.matching_lines.load(Ordering::Relaxed),
+            self.matches.load(Ordering::Relaxed),
+            seconds(&self.read_nanos),
+            seconds(&self.filter_nanos),
+            self.output_seconds(),
+            self.start.elapsed().as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use part13::search::FileStats;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stats_json() {
+        let stats = Stats::new();
+        let mut file_stats = FileStats::default();
+        file_stats.bytes = 100;
+        file_stats.read_time = Duration::from_millis(2);
+
+        stats.add_file(&file_stats, 3, 4, Duration::from_millis(5));
+        stats.add_file(&FileStats::default(), 0, 0, Duration::from_millis(1));
+
+        // Filtering is what is left of the time after reading.
+        assert!(stats.json().starts_with(
+            r#"{"files_searched":2,"bytes_read":100,"lines_scanned":0,"matching_lines":3,"matches":4,"elapsed_secs":{"reader":0.002000,"filter":0.004000,"#
+        ));
+    }
+}
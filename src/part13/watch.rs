@@ -0,0 +1,345 @@
+// In watch mode, rgrep keeps running like `tail -f` and reports the matches in the lines appended
+// to its files. Lines already in the files when we start are skipped. Files are polled: a reader
+// thread looks at every file in turn, a filter thread searches the new lines and the caller gets
+// the matches in its sink, just like the threads of a normal search.
+//
+// Log files do not only grow. If a file gets shorter, it was truncated, and we start reading it
+// from the beginning again. If the name refers to a different file than the one we have open, the
+// old one was rotated away: we read what was still written to it, and then continue with the new
+// file. Files that do not exist yet, or not anymore, are waited for.
+
+extern crate memchr;
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use self::memchr::memchr_iter;
+use part13::search::Matcher;
+use part13::{MatchedLine, Options, Sink};
+
+// How much we read at once when catching up with a file.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Tells files apart by their device and inode, which is what survives a rename. Elsewhere, we can
+// only notice truncation.
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Follows a single file. `pending` holds the beginning of a line whose terminator was not written
+// yet, which starts at `line_offset` in the file. Errors are reported once, until the file can be
+// read again.
+struct Follower {
+    file_name: Arc<String>,
+    file: Option<File>,
+    id: Option<(u64, u64)>,
+    position: u64,
+    line_number: usize,
+    line_offset: usize,
+    pending: Vec<u8>,
+    failed: bool,
+}
+
+impl Follower {
+    // Starts following `file_name` at its current end.
+    fn new(file_name: Arc<String>) -> Self {
+        let mut follower = Follower {
+            file_name,
+            file: None,
+            id: None,
+            position: 0,
+            line_number: 1,
+            line_offset: 0,
+            pending: Vec::new(),
+            failed: false,
+        };
+
+        let result = follower.open().and_then(|()| follower.read_appended(None));
+        follower.report(result);
+        follower
+    }
+
+    fn report(&mut self, result: io::Result<()>) {
+        match result {
+            Err(ref error) if !self.failed => eprintln!("rgrep: {}: {error}", self.file_name),
+            _ => {}
+        }
+        self.failed = result.is_err();
+    }
+
+    // Opens the file under our name, if there is one, to read it from the beginning.
+    fn open(&mut self) -> io::Result<()> {
+        let file = match File::open(self.file_name.as_str()) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+
+        self.id = file_id(&file.metadata()?);
+        self.file = Some(file);
+        self.position = 0;
+        self.line_number = 1;
+        self.line_offset = 0;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // Splits `data` into lines, which are added to `lines` unless we are just skipping them.
+    fn take_lines(&mut self, data: &[u8], mut lines: Option<&mut Vec<MatchedLine>>) {
+        let mut start = 0;
+
+        for end in memchr_iter(b'\n', data) {
+            let mut line = mem::take(&mut self.pending);
+            line.extend_from_slice(&data[start..end]);
+            let offset = self.line_offset;
+            self.line_offset += line.len() + 1;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            if let Some(ref mut lines) = lines {
+                lines.push(MatchedLine {
+                    file_name: self.file_name.clone(),
+                    file_index: 0,
+                    number: self.line_number,
+                    offset,
+                    line,
+                    matches: Vec::new(),
+                    binary: false,
+                });
+            }
+            self.line_number += 1;
+            start = end + 1;
+        }
+
+        self.pending.extend_from_slice(&data[start..]);
+    }
+
+    // Reads everything that was appended to the open file since we last looked.
+    fn read_appended(&mut self, mut lines: Option<&mut Vec<MatchedLine>>) -> io::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+        let mut chunk = vec![0; CHUNK_SIZE];
+
+        let result = (|| {
+            if file.metadata()?.len() < self.position {
+                file.seek(SeekFrom::Start(0))?;
+                self.position = 0;
+                self.line_number = 1;
+                self.line_offset = 0;
+                self.pending.clear();
+            }
+
+            loop {
+                let read = match file.read(&mut chunk) {
+                    Ok(0) => return Ok(()),
+                    Ok(read) => read,
+                    Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(error) => return Err(error),
+                };
+
+                self.position += read as u64;
+                self.take_lines(&chunk[..read], lines.as_deref_mut());
+            }
+        })();
+
+        self.file = Some(file);
+        result
+    }
+
+    // Looks for new lines, and for whether the file was rotated.
+    fn poll(&mut self, lines: &mut Vec<MatchedLine>) -> io::Result<()> {
+        self.read_appended(Some(lines))?;
+
+        let current_id = match fs::metadata(self.file_name.as_str()) {
+            Ok(metadata) => file_id(&metadata),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+
+        if self.file.is_none() || current_id != self.id {
+            // The last line of a rotated file will not be finished anymore.
+            if !self.pending.is_empty() {
+                self.take_lines(b"\n", Some(lines));
+            }
+            self.file = None;
+            self.open()?;
+            self.read_appended(Some(lines))?;
+        }
+
+        Ok(())
+    }
+}
+
+// The reader thread polls all files every `interval`, and sends the new lines of every round
+// together, until the search is `cancelled`.
+fn follow_files(
+    mut followers: Vec<Follower>,
+    interval: Duration,
+    cancelled: Arc<AtomicBool>,
+    out_channel: SyncSender<Vec<MatchedLine>>,
+) {
+    while !cancelled.load(Ordering::SeqCst) {
+        let mut lines = Vec::new();
+
+        for follower in followers.iter_mut() {
+            let result = follower.poll(&mut lines);
+            follower.report(result);
+        }
+
+        if !lines.is_empty() && out_channel.send(lines).is_err() {
+            return;
+        }
+        thread::sleep(interval);
+    }
+}
+
+// The filter thread keeps the lines that match, with their matches.
+fn filter_lines(
+    matcher: Matcher,
+    in_channel: Receiver<Vec<MatchedLine>>,
+    out_channel: SyncSender<MatchedLine>,
+) {
+    for lines in in_channel {
+        for mut matched_line in lines {
+            matched_line.matches = matcher.find_all(&matched_line.line);
+            if !matched_line.matches.is_empty() && out_channel.send(matched_line).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Follows `options.files`, checking for new lines every `interval`, and passes the matching ones to
+// `sink` until it has enough, or until there were `options.max_total` of them. Returns whether
+// anything matched, or an error right away if the patterns are invalid.
+pub fn watch(options: Arc<Options>, interval: Duration, sink: &mut impl Sink) -> io::Result<bool> {
+    let matcher = Matcher::new(&options)?;
+    let followers = options.files.iter().cloned().map(Follower::new).collect();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (line_sender, line_receiver) = sync_channel(16);
+    let (match_sender, match_receiver) = sync_channel(16);
+
+    let cancelled1 = cancelled.clone();
+    let reader = thread::spawn(move || follow_files(followers, interval, cancelled1, line_sender));
+    let filter = thread::spawn(move || filter_lines(matcher, line_receiver, match_sender));
+
+    let mut seen = 0;
+    let mut result = Ok(());
+    for matched_line in match_receiver.iter() {
+        seen += 1;
+        match sink.matched(matched_line) {
+            Ok(true) if options.max_total != Some(seen) => {}
+            Ok(_) => break,
+            Err(error) => {
+                result = Err(error);
+                break;
+            }
+        }
+    }
+
+    // Once the reader notices, both threads stop.
+    cancelled.store(true, Ordering::SeqCst);
+    drop(match_receiver);
+    reader.join().unwrap();
+    filter.join().unwrap();
+
+    result.and_then(|()| sink.finish())?;
+    Ok(seen > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::watch;
+    use part13::{MatchedLine, Options, OutputMode};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn append(path: &str, data: &str) {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_watch() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let log = dir.join("app.log").to_str().unwrap().to_string();
+        let rotated = dir.join("app.log.1").to_str().unwrap().to_string();
+        fs::write(&log, "let old\n").unwrap();
+
+        let options = Options {
+            max_total: Some(4),
+            files: vec![Arc::new(log.clone())],
+            output_mode: OutputMode::Watch,
+            ..Options::new(["let"])
+        };
+
+        // The first line is appended once the watch started, the others whenever a match comes
+        // in: the line is finished in two steps, then the file is truncated, and then rotated.
+        let writer = {
+            let log = log.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                append(&log, "no\nlet ");
+                thread::sleep(Duration::from_millis(50));
+                append(&log, "a\r\n");
+            })
+        };
+        let mut found = Vec::new();
+        let mut sink = |matched_line: MatchedLine| {
+            found.push((
+                matched_line.number,
+                matched_line.offset,
+                String::from_utf8(matched_line.line).unwrap(),
+            ));
+            match found.len() {
+                1 => fs::write(&log, "let b\n").unwrap(),
+                2 => {
+                    fs::rename(&log, &rotated).unwrap();
+                    append(&rotated, "let c\n");
+                    append(&log, "let d\n");
+                }
+                _ => {}
+            }
+            true
+        };
+
+        assert!(watch(Arc::new(options), Duration::from_millis(5), &mut sink).unwrap());
+        writer.join().unwrap();
+
+        assert_eq!(
+            found,
+            [
+                (3, 11, "let a".to_string()),
+                (1, 0, "let b".to_string()),
+                (2, 6, "let c".to_string()),
+                (1, 0, "let d".to_string()),
+            ]
+        );
+    }
+}
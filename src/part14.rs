@@ -8,9 +8,11 @@ pub fn sort<T: PartialOrd>(data: &mut [T]) {
         return;
     }
 
-    // We decide that the element at 0 is our pivot, and then we move our cursors through the rest
-    // of the slice, making sure that everything on the left is no larger than the pivot, and
-    // everything on the right is no smaller.
+    // We decide that the element in the middle is our pivot and move it to 0. Taking the first
+    // element instead would make sorting input that is already sorted take quadratic time. Then we
+    // move our cursors through the rest of the slice, making sure that everything on the left is no
+    // larger than the pivot, and everything on the right is no smaller.
+    data.swap(0, data.len() / 2);
     let mut lpos = 1;
     let mut rpos = data.len();
     /* Invariant: pivot is data[0]; everything with index (0,lpos) is <= pivot;
@@ -51,6 +53,7 @@ mod tests {
         assert_eq_vec(vec![5, 4, 3, 2, 1], vec![1, 2, 3, 4, 5]);
         assert_eq_vec(vec![1, 4, 3, 5, 2], vec![1, 2, 3, 4, 5]);
         assert_eq_vec(vec![3, 4, 2, 1, 5], vec![1, 2, 3, 4, 5]);
+        assert_eq_vec((0..100_000).collect(), (0..100_000).collect());
     }
 }
 
@@ -72,61 +75,753 @@ fn sort_array() {
 
 // ## External Dependencies
 
-// I disabled the following module (using a rather bad hack), because it only compiles if `docopt`
+// I disabled the following module (using a rather bad hack), because it only compiles if `clap`
 // is linked. Remove the attribute of the `rgrep` module to enable compilation.
 // #[cfg(feature = "disabled")]
 pub mod rgrep {
-    // Now that `docopt` is linked, we can first add it to the namespace with `extern crate` and
-    // then import shorter names with `use`. We also import some other pieces that we will need.
-    extern crate docopt;
-    use self::docopt::Docopt;
-    use part13::{run, Options, OutputMode};
-    use std::process;
-
-    // The `USAGE` string documents how the program is to be called. It's written in a format that
-    // `docopt` can parse.
-    static USAGE: &'static str = "
-Usage: rgrep [-r] [-c] [-s] <pattern> <file>...
-
-Options:
-    -r, --regexp  Switch the pattern to regular-expression mode.
-    -c, --count   Count number of matching lines (rather than printing them).
-    -s, --sort    Sort the lines before printing.
-";
+    // Now that `clap` is linked, we can first add it to the namespace with `extern crate` and then
+    // import shorter names with `use`. We also import some other pieces that we will need.
+    extern crate clap;
+    extern crate clap_complete;
+    extern crate clap_mangen;
+    use self::clap::error::ErrorKind;
+    use self::clap::{Arg, Command, CommandFactory, Parser, ValueEnum};
+    use self::clap_complete::Shell;
+    use self::clap_mangen::Man;
+    use part13::{
+        discover_files, run, BinaryMode, ColorChoice, FileFilter, LineFormat, Options, OutputMode,
+        SortKey, SortOrder,
+    };
+    use std::io::Write;
+    use std::{env, fs, io, process, thread};
+
+    // The arguments are described by a struct, from which `clap` derives the parser, the `--help`
+    // text, the shell completions and the man page. Every option says which others it cannot be
+    // combined with, so `clap` reports such mistakes for us. Of the output modes, only one can be
+    // chosen, which is what the "mode" group is for.
+    #[derive(Parser)]
+    #[command(
+        name = "rgrep",
+        bin_name = "rgrep",
+        version,
+        about = "Search files for lines matching a pattern.",
+        after_help = "Without files, or with '-' as a file, rgrep reads standard input. Directories \
+                      are searched recursively, skipping hidden files and directories.\n\n\
+                      Options can be given defaults in the file named by the RGREP_CONFIG \
+                      environment variable, one per line, like '--color=always' or '-j 4'. Empty \
+                      lines and lines starting with '#' are ignored. Options given on the command \
+                      line replace those from the file."
+    )]
+    struct Args {
+        #[arg(
+            help = "The pattern to search for, unless patterns are given with '-e' or '-f'.",
+            required_unless_present_any = ["patterns", "pattern_files", "generate"]
+        )]
+        pattern: Option<String>,
+        #[arg(help = "The files and directories to search.")]
+        files: Vec<String>,
+
+        #[arg(short, long, help = "Switch the pattern to regular-expression mode.")]
+        regexp: bool,
+        #[arg(
+            short,
+            long,
+            help = "Let upper and lower case letters match each other, also outside of ASCII."
+        )]
+        ignore_case: bool,
+        #[arg(
+            short = 'U',
+            long,
+            help = "Let matches span several lines, like '\\(\\s*\\n'. Every match is printed \
+                    with all the lines it touches."
+        )]
+        multiline: bool,
+        #[arg(
+            short = 'e',
+            long = "pattern",
+            value_name = "PAT",
+            allow_hyphen_values = true,
+            help = "Search for <PAT> instead of the first argument. Given several times, lines \
+                    matching any of the patterns are found."
+        )]
+        patterns: Vec<String>,
+        #[arg(
+            short = 'f',
+            long = "file",
+            value_name = "PATFILE",
+            help = "Read patterns from <PATFILE>, one per line, after those given with '-e'. \
+                    Empty lines are ignored."
+        )]
+        pattern_files: Vec<String>,
+
+        #[arg(
+            short,
+            long,
+            group = "mode",
+            help = "Count number of matching lines (rather than printing them)."
+        )]
+        count: bool,
+        #[arg(
+            long,
+            group = "mode",
+            help = "Print the number of matching lines of every file with a match."
+        )]
+        count_per_file: bool,
+        #[arg(
+            short = 'l',
+            long,
+            group = "mode",
+            help = "Only print the names of files with a match."
+        )]
+        files_with_matches: bool,
+        #[arg(
+            short = 'L',
+            long,
+            group = "mode",
+            help = "Only print the names of files without a match."
+        )]
+        files_without_match: bool,
+        #[arg(
+            short = 's',
+            group = "mode",
+            help = "Sort the lines by their content before printing."
+        )]
+        sort_by_content: bool,
+        #[arg(
+            long,
+            value_name = "KEY",
+            group = "mode",
+            help = "Sort the lines before printing by path, line (number), content or none at all."
+        )]
+        sort: Option<SortBy>,
+        #[arg(
+            long,
+            value_name = "KEY",
+            group = "mode",
+            help = "Like '--sort', but in reverse order."
+        )]
+        sortr: Option<SortBy>,
+        #[arg(
+            long,
+            value_name = "SIZE",
+            default_value = "256M",
+            value_parser = parse_size,
+            help = "How much memory sorting may use, like '512K', '64M' or '2G'. Lines beyond \
+                    that are sorted in temporary files."
+        )]
+        sort_memory: usize,
+        #[arg(
+            long,
+            group = "mode",
+            help = "Print one JSON object per line for every file, match and a final summary."
+        )]
+        json: bool,
+        #[arg(
+            short = 'W',
+            long,
+            group = "mode",
+            conflicts_with_all = ["multiline", "stats", "search_zip", "max_count"],
+            help = "Keep running, and print the matches in lines appended to the files, like \
+                    'tail -f'. Truncated and rotated files are followed as well."
+        )]
+        watch: bool,
+        #[arg(
+            long,
+            help = "Print how many files, bytes and lines were searched, how many matches were \
+                    found, and how long reading, filtering and printing took. With '--json', they \
+                    are part of the summary."
+        )]
+        stats: bool,
+        #[arg(
+            short,
+            long,
+            group = "mode",
+            help = "Print nothing, and stop at the first match. The exit status is 0 if there was \
+                    a match, 1 if there was none, and 2 on errors."
+        )]
+        quiet: bool,
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            value_parser = parse_positive,
+            help = "Stop searching a file after <N> matching lines."
+        )]
+        max_count: Option<usize>,
+        #[arg(
+            long,
+            value_name = "N",
+            value_parser = parse_positive,
+            help = "Stop searching after <N> matching lines altogether."
+        )]
+        max_total: Option<usize>,
+        #[arg(
+            short,
+            long,
+            group = "mode",
+            help = "Print every match on a line of its own."
+        )]
+        only_matching: bool,
+        #[arg(
+            long,
+            value_name = "TEMPLATE",
+            group = "mode",
+            help = "Print lines with every match replaced by <TEMPLATE>, which may refer to \
+                    capture groups as `$1` or `${name}`."
+        )]
+        replace: Option<String>,
+        #[arg(
+            long,
+            requires = "replace",
+            conflicts_with_all = ["multiline", "stats"],
+            help = "Together with '--replace', rewrite the files instead of printing."
+        )]
+        in_place: bool,
+        #[arg(
+            long,
+            requires = "in_place",
+            help = "Together with '--in-place', print a unified diff of the changes instead of \
+                    writing them."
+        )]
+        dry_run: bool,
+
+        #[arg(
+            short = 'n',
+            long,
+            overrides_with = "no_line_number",
+            help = "Print the number of every line, counting from 1. This is the default."
+        )]
+        line_number: bool,
+        #[arg(
+            short = 'N',
+            long,
+            overrides_with = "line_number",
+            help = "Do not print line numbers."
+        )]
+        no_line_number: bool,
+        #[arg(
+            long,
+            help = "Print the column of the first match in every line, counting bytes from 1."
+        )]
+        column: bool,
+        #[arg(
+            short = 'b',
+            long,
+            help = "Print the byte offset of every line in its file, or of every match with \
+                    '--only-matching'."
+        )]
+        byte_offset: bool,
+        #[arg(
+            short = 'H',
+            long,
+            overrides_with = "no_filename",
+            help = "Print the file name in front of every line. This is the default."
+        )]
+        with_filename: bool,
+        #[arg(
+            long,
+            overrides_with = "with_filename",
+            help = "Do not print file names in front of the lines."
+        )]
+        no_filename: bool,
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "count", "count_per_file", "files_with_matches", "files_without_match", "json",
+                "quiet", "only_matching", "no_line_number", "no_filename", "byte_offset",
+            ],
+            help = "Print every match on a line of its own as 'file:line:column:text', the way \
+                    vim reads search results."
+        )]
+        vimgrep: bool,
+
+        #[arg(
+            short,
+            long,
+            value_name = "GLOB",
+            help = "Only search files matching <GLOB>, like '*.rs', or not matching it if it \
+                    starts with '!', like '!*.lock'. Globs without a '/' match the file name in \
+                    any directory."
+        )]
+        glob: Vec<String>,
+        #[arg(
+            short,
+            long = "type",
+            value_name = "TYPE",
+            help = "Only search files of the given type, like 'rust'."
+        )]
+        types: Vec<String>,
+        #[arg(
+            short = 'T',
+            long = "type-not",
+            value_name = "TYPE",
+            help = "Do not search files of the given type."
+        )]
+        not_types: Vec<String>,
+        #[arg(short = 'a', long, help = "Search binary files as if they were text.")]
+        binary: bool,
+        #[arg(
+            long,
+            conflicts_with = "binary",
+            help = "Do not search binary files at all. By default, binary files are searched, but \
+                    only reported as 'Binary file X matches'."
+        )]
+        skip_binary: bool,
+        #[arg(
+            short = 'z',
+            long,
+            help = "Decompress gzip-compressed files while searching them."
+        )]
+        search_zip: bool,
+        #[arg(
+            long,
+            help = "Memory-map large files instead of reading them. Faster, but the files must \
+                    not change while rgrep runs."
+        )]
+        mmap: bool,
+        #[arg(
+            short = 'j',
+            long,
+            value_name = "N",
+            value_parser = parse_positive,
+            help = "Number of threads searching files in parallel. Defaults to the number of \
+                    available CPUs."
+        )]
+        threads: Option<usize>,
+        #[arg(
+            long,
+            value_name = "WHEN",
+            default_value = "auto",
+            help = "Highlight matches, file names and line numbers."
+        )]
+        color: When,
+        #[arg(long, help = "Ignore the file named by RGREP_CONFIG.")]
+        no_config: bool,
+        #[arg(
+            long,
+            value_name = "KIND",
+            exclusive = true,
+            help = "Print the completions for a shell, or the man page, instead of searching."
+        )]
+        generate: Option<Generate>,
+    }
+
+    #[derive(Clone, Copy, ValueEnum)]
+    enum SortBy {
+        Path,
+        Line,
+        Content,
+        None,
+    }
+
+    #[derive(Clone, Copy, ValueEnum)]
+    enum When {
+        Auto,
+        Always,
+        Never,
+    }
+
+    #[derive(Clone, Copy, ValueEnum)]
+    enum Generate {
+        Bash,
+        Zsh,
+        Fish,
+        Man,
+    }
+
+    // Reads the value of an option that needs a positive number.
+    fn parse_positive(n: &str) -> Result<usize, String> {
+        match n.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err("expected a positive number".to_string()),
+        }
+    }
+
+    // Reads a size in bytes, which may be given in kibi-, mebi- or gibibytes.
+    fn parse_size(size: &str) -> Result<usize, String> {
+        let (digits, unit) = match size.char_indices().last() {
+            Some((i, 'K')) => (&size[..i], 1 << 10),
+            Some((i, 'M')) => (&size[..i], 1 << 20),
+            Some((i, 'G')) => (&size[..i], 1 << 30),
+            _ => (size, 1),
+        };
+
+        digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(unit))
+            .ok_or_else(|| "expected a size like '512K', '64M' or '2G'".to_string())
+    }
+
+    // The command as `clap` sees it once it is built, which is when the groups come into being.
+    fn built_command() -> Command {
+        let mut command = Args::command();
+        command.build();
+        command
+    }
+
+    // The options of the command, as opposed to its positional arguments.
+    fn options(command: &Command) -> Vec<&Arg> {
+        command
+            .get_arguments()
+            .filter(|arg| !arg.is_positional())
+            .collect()
+    }
+
+    // The options by their names, like `["-j", "--threads"]`, and whether they take a value.
+    fn option_names() -> Vec<(Vec<String>, bool)> {
+        options(&built_command())
+            .into_iter()
+            .map(|arg| {
+                let mut names = Vec::new();
+                if let Some(short) = arg.get_short() {
+                    names.push(format!("-{short}"));
+                }
+                if let Some(long) = arg.get_long() {
+                    names.push(format!("--{long}"));
+                }
+
+                (names, arg.get_action().takes_values())
+            })
+            .collect()
+    }
+
+    // The pairs of options, as indices into `option_names`, that cannot be combined: those that say
+    // so, either way round, and any two of the "mode" group.
+    fn conflicting_options() -> Vec<(usize, usize)> {
+        let command = built_command();
+        let options = options(&command);
+        let index = |arg: &Arg| {
+            options
+                .iter()
+                .position(|option| option.get_id() == arg.get_id())
+        };
+        let mut conflicts = Vec::new();
+
+        for (i, &option) in options.iter().enumerate() {
+            for other in command.get_arg_conflicts_with(option) {
+                if let Some(j) = index(other) {
+                    conflicts.extend([(i, j), (j, i)]);
+                }
+            }
+        }
+
+        let modes: Vec<usize> = command
+            .get_groups()
+            .filter(|group| group.get_id() == "mode")
+            .flat_map(|group| group.get_args())
+            .filter_map(|id| options.iter().position(|option| option.get_id() == id))
+            .collect();
+        for &i in &modes {
+            conflicts.extend(modes.iter().filter(|&&j| j != i).map(|&j| (i, j)));
+        }
+
+        conflicts
+    }
+
+    // Finds the options in `args`, as indices into `names`. Short options may be combined, like
+    // `-rc`, and the last of them may be followed by its value, like `-j4`. Everything after `--`
+    // is an argument.
+    fn given_options(names: &[(Vec<String>, bool)], args: &[String]) -> Vec<usize> {
+        let find = |name: String| names.iter().position(|(names, _)| names.contains(&name));
+        let mut given = Vec::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+
+            if let Some(long) = arg.strip_prefix("--") {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, _)) => (name, true),
+                    None => (long, false),
+                };
+
+                if let Some(index) = find(format!("--{name}")) {
+                    given.push(index);
+                    if names[index].1 && !value {
+                        args.next();
+                    }
+                }
+            } else if arg.len() > 1 && arg.starts_with('-') {
+                for (i, short) in arg.char_indices().skip(1) {
+                    let Some(index) = find(format!("-{short}")) else {
+                        continue;
+                    };
+
+                    given.push(index);
+                    if names[index].1 {
+                        if i + short.len_utf8() == arg.len() {
+                            args.next();
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        given
+    }
+
+    // Puts the options from the `config` file in front of the command-line `args`, after the name
+    // of the program. Every line holds one option, possibly followed by its value. Options that are
+    // given on the command line, under any of their names, are left out, and so are those that
+    // cannot be combined with them. Choosing an output mode on the command line thus replaces the
+    // one from the config file.
+    fn with_config(config: &str, args: Vec<String>) -> Vec<String> {
+        let names = option_names();
+        let conflicts = conflicting_options();
+        let given = given_options(&names, &args[1..]);
+        let overridden = |index: &usize| {
+            given
+                .iter()
+                .any(|&given| given == *index || conflicts.contains(&(given, *index)))
+        };
+        let mut with_config = vec![args[0].clone()];
+
+        for line in config.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line_args: Vec<String> = match line.split_once(char::is_whitespace) {
+                Some((option, value)) => vec![option.to_string(), value.trim().to_string()],
+                None => vec![line.to_string()],
+            };
+            if !given_options(&names, &line_args).iter().any(overridden) {
+                with_config.extend(line_args);
+            }
+        }
+
+        with_config.extend(args.into_iter().skip(1));
+        with_config
+    }
+
+    // Reports a mistake on the command line like `clap` does, on stderr, and exits.
+    fn fail(kind: ErrorKind, message: impl std::fmt::Display) -> ! {
+        Args::command().error(kind, message).exit()
+    }
+
+    // The command-line arguments, with the defaults from the config file unless there is none or
+    // '--no-config' was given. '--generate' cannot be combined with any other option, so it does
+    // not get the config either.
+    fn args_with_config() -> Vec<String> {
+        let args: Vec<String> = env::args().collect();
+        let no_config = args
+            .iter()
+            .take_while(|&arg| arg != "--")
+            .any(|arg| arg == "--no-config" || arg.starts_with("--generate"));
+
+        match env::var_os("RGREP_CONFIG") {
+            Some(ref path) if !no_config && !path.is_empty() => {
+                let config = fs::read_to_string(path).unwrap_or_else(|error| {
+                    fail(
+                        ErrorKind::Io,
+                        format!(
+                            "cannot read the config file '{}': {error}",
+                            path.to_string_lossy()
+                        ),
+                    )
+                });
+
+                with_config(&config, args)
+            }
+            _ => args,
+        }
+    }
+
+    // Prints what `--generate` asked for, all derived from `Args`. The completions are generated
+    // into a buffer first, as `clap_complete` panics if it cannot write them.
+    fn generate(kind: Generate) -> io::Result<()> {
+        let mut command = Args::command();
+        let shell = match kind {
+            Generate::Bash => Shell::Bash,
+            Generate::Zsh => Shell::Zsh,
+            Generate::Fish => Shell::Fish,
+            Generate::Man => return Man::new(command).render(&mut io::stdout()),
+        };
+        let mut completions = Vec::new();
+
+        clap_complete::generate(shell, &mut command, "rgrep", &mut completions);
+        io::stdout().write_all(&completions)
+    }
+
+    // Reads the order from '-s', '--sort' or '--sortr'. Sorting by "none" is the same as not
+    // sorting.
+    fn get_sort_order(args: &Args) -> Option<SortOrder> {
+        let (sort_by, reverse) = match (args.sort_by_content, args.sort, args.sortr) {
+            (true, _, _) => (SortBy::Content, false),
+            (_, Some(sort_by), _) => (sort_by, false),
+            (_, _, Some(sort_by)) => (sort_by, true),
+            _ => return None,
+        };
+        let key = match sort_by {
+            SortBy::Path => SortKey::Path,
+            SortBy::Line => SortKey::Line,
+            SortBy::Content => SortKey::Content,
+            SortBy::None => return None,
+        };
+
+        Some(SortOrder { key, reverse })
+    }
+
+    fn strs(values: &[String]) -> Vec<&str> {
+        values.iter().map(String::as_str).collect()
+    }
+
+    fn has_pattern_options(args: &Args) -> bool {
+        !args.patterns.is_empty() || !args.pattern_files.is_empty()
+    }
+
+    // Collects the patterns given with '-e' and read from the files given with '-f', or, if there
+    // are none of these, the one pattern given as the first argument.
+    fn get_patterns(args: &Args) -> Vec<String> {
+        let mut patterns = args.patterns.clone();
+
+        for file in &args.pattern_files {
+            let contents = fs::read_to_string(file).unwrap_or_else(|error| {
+                fail(
+                    ErrorKind::Io,
+                    format!("cannot read patterns from '{file}': {error}"),
+                )
+            });
+
+            patterns.extend(
+                contents
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.to_string()),
+            );
+        }
+
+        if !has_pattern_options(args) {
+            patterns.extend(args.pattern.clone());
+        }
+
+        if patterns.is_empty() {
+            fail(
+                ErrorKind::MissingRequiredArgument,
+                "there are no patterns to search for",
+            );
+        }
+
+        patterns
+    }
 
     // This function extracts the rgrep options from the command-line arguments.
     fn get_options() -> Options {
-        // This parses `argv` and exit the program with an error message if it fails. The code is
-        // taken from the [`docopt` documentation](http://burntsushi.net/rustdoc/docopt/). <br/>
-        let args = Docopt::new(USAGE)
-            .and_then(|d| d.parse())
-            .unwrap_or_else(|e| e.exit());
-        // Now we can get all the values out.
-        let use_regexp_mode = args.get_bool("-r");
-        let count = args.get_bool("-c");
-        let sort = args.get_bool("-s");
-        let pattern = args.get_str("<pattern>");
-        let files = args.get_vec("<file>");
-
-        if count && sort {
-            println!("Setting both '-c' and '-s' at the same time does not make any sense.");
-            process::exit(1);
-        }
-
-        // We need to make the strings owned to construct the `Options` instance.
-        let mode = if count {
+        // This parses `argv` and exits the program with an error message on stderr if it fails.
+        // `--help` and `--version` are handled the same way, printing to stdout.
+        let args = Args::parse_from(args_with_config());
+
+        if let Some(kind) = args.generate {
+            generate(kind).unwrap_or_else(|error| fail(ErrorKind::Io, error));
+            process::exit(0);
+        }
+
+        let patterns = get_patterns(&args);
+        let mut files = args.files.clone();
+        // With '-e' or '-f', the first argument is not a pattern, but the first file.
+        if has_pattern_options(&args) {
+            files.splice(0..0, args.pattern.clone());
+        }
+        let filter = FileFilter::new(
+            &strs(&args.glob),
+            &strs(&args.types),
+            &strs(&args.not_types),
+        )
+        .unwrap_or_else(|error| fail(ErrorKind::InvalidValue, error));
+        let threads = args
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let binary = match (args.binary, args.skip_binary) {
+            (true, _) => BinaryMode::Text,
+            (_, true) => BinaryMode::Skip,
+            _ => BinaryMode::Detect,
+        };
+        let color = match args.color {
+            When::Auto => ColorChoice::Auto,
+            When::Always => ColorChoice::Always,
+            When::Never => ColorChoice::Never,
+        };
+        // Line numbers and file names are shown unless they are turned off, and the last of '-n'
+        // and '-N' wins, just like with '-H' and '--no-filename'.
+        let line_format = LineFormat {
+            file_name: !args.no_filename,
+            line_number: !args.no_line_number,
+            column: args.column,
+            byte_offset: args.byte_offset,
+            vimgrep: args.vimgrep,
+        };
+
+        if args.in_place && (files.is_empty() || files.iter().any(|file| file == "-")) {
+            fail(
+                ErrorKind::ArgumentConflict,
+                "'--in-place' cannot rewrite standard input",
+            );
+        }
+
+        if args.watch && (files.is_empty() || files.iter().any(|file| file == "-")) {
+            fail(
+                ErrorKind::ArgumentConflict,
+                "'--watch' needs files, it cannot follow standard input",
+            );
+        }
+
+        // The "mode" group makes sure that at most one of these is set.
+        let mode = if args.count {
             OutputMode::Count
-        } else if sort {
-            OutputMode::SortAndPrint
+        } else if args.count_per_file {
+            OutputMode::CountPerFile
+        } else if args.files_with_matches {
+            OutputMode::FilesWithMatches
+        } else if args.files_without_match {
+            OutputMode::FilesWithoutMatch
+        } else if let Some(order) = get_sort_order(&args) {
+            OutputMode::SortAndPrint(order)
+        } else if args.json {
+            OutputMode::Json
+        } else if args.only_matching {
+            OutputMode::OnlyMatching
+        } else if args.quiet {
+            OutputMode::Quiet
+        } else if args.watch {
+            OutputMode::Watch
+        } else if let Some(ref template) = args.replace {
+            if args.in_place {
+                OutputMode::ReplaceInPlace {
+                    template: template.clone(),
+                    dry_run: args.dry_run,
+                }
+            } else {
+                OutputMode::Replace(template.clone())
+            }
         } else {
             OutputMode::Print
         };
 
         Options {
-            files: files.iter().map(|file| file.to_string().into()).collect(),
-            pattern: pattern.to_string(),
+            files: if files.is_empty() {
+                vec!["-".to_string().into()]
+            } else {
+                discover_files(&strs(&files), &filter)
+            },
+            patterns,
             output_mode: mode,
-            use_regexp_mode,
+            use_regexp_mode: args.regexp,
+            ignore_case: args.ignore_case,
+            threads,
+            mmap: args.mmap,
+            search_zip: args.search_zip,
+            multiline: args.multiline,
+            max_count: args.max_count,
+            max_total: args.max_total,
+            sort_memory: args.sort_memory,
+            stats: args.stats,
+            binary,
+            line_format,
+            color,
         }
     }
 
@@ -135,7 +830,132 @@ Options:
     // You can now use `cargo run -- <pattern> <files>` to call your program, and see the argument
     // parser and the threads we wrote previously in action!
     pub fn main() {
-        run(get_options());
+        let options = get_options();
+        let quiet = matches!(options.output_mode, OutputMode::Quiet);
+
+        match run(options) {
+            Ok(matched) => {
+                if quiet && !matched {
+                    process::exit(1);
+                }
+            }
+            // Whoever reads our output, like `head`, may stop before we are done. That's fine.
+            Err(ref error) if error.kind() == io::ErrorKind::BrokenPipe => {}
+            // Like `grep`, we keep 1 for "nothing matched", and tell errors apart with 2.
+            Err(error) => {
+                eprintln!("rgrep: {error}");
+                process::exit(2);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::clap::error::ErrorKind;
+        use super::clap::{CommandFactory, Parser};
+        use super::{with_config, Args};
+
+        const CONFIG: &str = "
+# Always color, but only Rust files.
+--color=always
+-j 2
+
+  --glob=*.rs
+-a
+";
+
+        fn parse_with(config: &str, args: &[&str]) -> Args {
+            let args = args.iter().map(|arg| arg.to_string()).collect();
+
+            Args::try_parse_from(with_config(config, args)).unwrap()
+        }
+
+        fn parse(args: &[&str]) -> Args {
+            parse_with(CONFIG, args)
+        }
+
+        #[test]
+        fn test_args() {
+            Args::command().debug_assert();
+
+            let error = |args: &[&str]| Args::try_parse_from(args).err().unwrap().kind();
+            assert_eq!(
+                error(&["rgrep", "-c", "-l", "x"]),
+                ErrorKind::ArgumentConflict
+            );
+            assert_eq!(
+                error(&["rgrep", "-s", "--sortr=path", "x"]),
+                ErrorKind::ArgumentConflict
+            );
+            assert_eq!(
+                error(&["rgrep", "-a", "--skip-binary", "x"]),
+                ErrorKind::ArgumentConflict
+            );
+            assert_eq!(
+                error(&["rgrep", "--in-place", "x", "f"]),
+                ErrorKind::MissingRequiredArgument
+            );
+            assert_eq!(
+                error(&["rgrep", "-j", "0", "x"]),
+                ErrorKind::ValueValidation
+            );
+            assert_eq!(
+                error(&["rgrep", "--color=sometimes", "x"]),
+                ErrorKind::InvalidValue
+            );
+            assert_eq!(error(&["rgrep"]), ErrorKind::MissingRequiredArgument);
+            assert_eq!(
+                error(&["rgrep", "--vimgrep", "-o", "x"]),
+                ErrorKind::ArgumentConflict
+            );
+
+            // Of '-n' and '-N', the last one counts.
+            let args = Args::try_parse_from(["rgrep", "-N", "-n", "--no-filename", "x"]).unwrap();
+            assert!(!args.no_line_number && args.no_filename);
+            let args = Args::try_parse_from(["rgrep", "-n", "-N", "x"]).unwrap();
+            assert!(args.no_line_number);
+
+            let args =
+                Args::try_parse_from(["rgrep", "--sort-memory=2K", "-e", "-x", "f"]).unwrap();
+            assert_eq!(args.sort_memory, 2048);
+            assert_eq!(args.patterns, ["-x"]);
+            assert_eq!(args.pattern.as_deref(), Some("f"));
+            assert!(Args::try_parse_from(["rgrep", "--generate=fish"]).is_ok());
+        }
+
+        #[test]
+        fn test_with_config() {
+            let args = parse(&["rgrep", "let", "src"]);
+            assert!(matches!(args.color, super::When::Always));
+            assert_eq!(args.threads, Some(2));
+            assert_eq!(args.glob, ["*.rs"]);
+            assert!(args.binary);
+            assert_eq!(args.files, ["src"]);
+
+            // The command line wins, whichever name it uses for an option.
+            let args = parse(&["rgrep", "--color=never", "-g", "*.md", "-aj4", "let"]);
+            assert!(matches!(args.color, super::When::Never));
+            assert_eq!(args.threads, Some(4));
+            assert_eq!(args.glob, ["*.md"]);
+            assert!(args.binary);
+
+            let args = parse(&["rgrep", "--threads", "8", "--", "-a"]);
+            assert_eq!(args.threads, Some(8));
+            assert_eq!(args.pattern.as_deref(), Some("-a"));
+
+            // Options that cannot be combined with those on the command line are left out, and so
+            // is the output mode if the command line chooses another one.
+            let args = parse_with("-c\n", &["rgrep", "-i", "x"]);
+            assert!(args.count);
+            let args = parse_with("-c\n", &["rgrep", "-l", "x"]);
+            assert!(args.files_with_matches && !args.count);
+            let args = parse_with("-s\n", &["rgrep", "--sort=path", "x"]);
+            assert!(args.sort.is_some() && !args.sort_by_content);
+            let args = parse_with("--vimgrep\n", &["rgrep", "-N", "x"]);
+            assert!(args.no_line_number && !args.vimgrep);
+            let args = parse_with("--skip-binary\n", &["rgrep", "-a", "x"]);
+            assert!(args.binary && !args.skip_binary);
+        }
     }
 }
 
@@ -0,0 +1,492 @@
+// End-to-end tests of rgrep: every test builds a small tree of files in a temporary directory, runs
+// the binary in there with some flags, and looks at what it prints and how it exits. The unit tests
+// next to the code cover the pieces; these make sure that the command line puts them together the
+// way the `--help` text says.
+
+extern crate assert_cmd;
+extern crate flate2;
+extern crate predicates;
+extern crate tempfile;
+
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::Command;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use predicates::prelude::*;
+use predicates::str::contains;
+use tempfile::TempDir;
+
+// The tree most tests search. File names are relative to its root, which is where rgrep runs.
+const FILES: &[(&str, &str)] = &[
+    (
+        "src/main.rs",
+        "fn main() {\n    let x = 1;\n    let y = x;\n}\n",
+    ),
+    ("src/lib.rs", "pub fn f() {}\n"),
+    ("notes.txt", "Let it be\nnothing else\n"),
+    (".hidden/secret.rs", "let hidden = true;\n"),
+];
+
+fn tree(files: &[(&str, &str)]) -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+
+    for (name, contents) in files {
+        let path = dir.path().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    dir
+}
+
+// Runs rgrep in `dir`, without the config file the user running the tests may have.
+fn rgrep(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rust-101-workspace"));
+
+    command
+        .current_dir(dir)
+        .env_remove("RGREP_CONFIG")
+        .args(args);
+    command
+}
+
+#[test]
+fn test_print() {
+    let dir = tree(FILES);
+
+    // Directories are searched recursively in the order of their names, skipping hidden ones, and
+    // lines are numbered from 1.
+    rgrep(dir.path(), &["let", "src", "notes.txt"])
+        .assert()
+        .success()
+        .stdout("src/main.rs:2:     let x = 1;\nsrc/main.rs:3:     let y = x;\n")
+        .stderr("");
+
+    // Nothing found is not an error.
+    rgrep(dir.path(), &["nowhere", "src"])
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn test_stdin() {
+    let dir = tree(&[]);
+
+    rgrep(dir.path(), &["b"])
+        .write_stdin("a\nb\n")
+        .assert()
+        .success()
+        .stdout("(standard input):2: b\n");
+    rgrep(dir.path(), &["--in-place", "--replace=x", "b"])
+        .write_stdin("b\n")
+        .assert()
+        .failure()
+        .stderr(contains("cannot rewrite standard input"));
+}
+
+#[test]
+fn test_stdin_slow() {
+    let mut child = process::Command::new(env!("CARGO_BIN_EXE_rust-101-workspace"))
+        .env_remove("RGREP_CONFIG")
+        .arg("b")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    // A match has to show up while the writer is still busy, not only once it is done.
+    stdin.write_all(b"a\nb\n").unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        sender.send(line).unwrap();
+    });
+    let line = receiver.recv_timeout(Duration::from_secs(20));
+
+    drop(stdin);
+    child.wait().unwrap();
+    reader.join().unwrap();
+    assert_eq!(line.unwrap(), "(standard input):2: b\n");
+}
+
+#[test]
+fn test_closed_pipe() {
+    let dir = tree(&[]);
+    fs::write(dir.path().join("big.txt"), "a line\n".repeat(100_000)).unwrap();
+
+    // Whoever reads the output may stop early, whatever the output mode.
+    for args in [
+        &["-o", "line"][..],
+        &["--replace=x", "line"],
+        &["-l", "line", "big.txt", "big.txt", "big.txt"],
+        &["--json", "line"],
+        &["--sortr=content", "--sort-memory=1M", "line"],
+    ] {
+        let mut child = process::Command::new(env!("CARGO_BIN_EXE_rust-101-workspace"))
+            .current_dir(dir.path())
+            .env_remove("RGREP_CONFIG")
+            .args(args)
+            .args(["big.txt", "big.txt"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "", "{args:?}");
+    }
+}
+
+#[test]
+fn test_patterns() {
+    let dir = tree(FILES);
+    fs::write(dir.path().join("patterns"), "fn f\n\nlet y\n").unwrap();
+
+    rgrep(dir.path(), &["-r", r"let \w = \d", "src"])
+        .assert()
+        .stdout("src/main.rs:2:     let x = 1;\n");
+    rgrep(dir.path(), &["-i", "LET IT", "."])
+        .assert()
+        .stdout("./notes.txt:1: Let it be\n");
+    rgrep(dir.path(), &["-f", "patterns", "src"])
+        .assert()
+        .stdout("src/lib.rs:1: pub fn f() {}\nsrc/main.rs:3:     let y = x;\n");
+    rgrep(dir.path(), &["-e", "main", "-e", "be", "src", "notes.txt"])
+        .assert()
+        .stdout("src/main.rs:1: fn main() {\nnotes.txt:1: Let it be\n");
+    rgrep(dir.path(), &["-U", "-r", r"\{\n\s+let", "src"])
+        .assert()
+        .stdout("src/main.rs:1: fn main() {\n    let x = 1;\n");
+    // Replacing finds the same matches, with anchors at every line.
+    rgrep(
+        dir.path(),
+        &["-U", "--replace=<$0>", "-r", r"\{\n^\s+let", "src"],
+    )
+    .assert()
+    .stdout("src/main.rs:1: fn main() <{\n    let> x = 1;\n");
+}
+
+#[test]
+fn test_invalid_regex() {
+    let dir = tree(FILES);
+
+    rgrep(dir.path(), &["-r", "(let", "src"])
+        .assert()
+        .code(2)
+        .stdout("")
+        .stderr(contains("rgrep: regex parse error").and(contains("unclosed group")));
+    // The same pattern is fine as a literal.
+    rgrep(dir.path(), &["(let", "src"]).assert().success();
+}
+
+#[test]
+fn test_line_format() {
+    let dir = tree(FILES);
+
+    rgrep(dir.path(), &["-N", "--no-filename", "x", "src/main.rs"])
+        .assert()
+        .stdout("    let x = 1;\n    let y = x;\n");
+    rgrep(dir.path(), &["--column", "-b", "y", "src/main.rs"])
+        .assert()
+        .stdout("src/main.rs:3:9:27:     let y = x;\n");
+    rgrep(dir.path(), &["-ob", "x", "src/main.rs"])
+        .assert()
+        .stdout("src/main.rs:2:20: x\nsrc/main.rs:3:39: x\n");
+    rgrep(dir.path(), &["--vimgrep", "-r", "[xy]", "src/main.rs"])
+        .assert()
+        .stdout(
+            "src/main.rs:2:9:    let x = 1;\n\
+             src/main.rs:3:9:    let y = x;\n\
+             src/main.rs:3:13:    let y = x;\n",
+        );
+    rgrep(dir.path(), &["--color=always", "y", "src/main.rs"])
+        .assert()
+        .stdout("\x1b[35msrc/main.rs\x1b[0m:\x1b[32m3\x1b[0m:     let \x1b[1;31my\x1b[0m = x;\n");
+}
+
+#[test]
+fn test_modes() {
+    let dir = tree(FILES);
+
+    rgrep(dir.path(), &["-c", "let", "."])
+        .assert()
+        .stdout("2 hits for let.\n");
+    rgrep(dir.path(), &["--count-per-file", "fn", "."])
+        .assert()
+        .stdout("./src/lib.rs:1\n./src/main.rs:1\n");
+    rgrep(
+        dir.path(),
+        &["--count-per-file", "let", "src/main.rs", "src/main.rs"],
+    )
+    .assert()
+    .stdout("src/main.rs:2\nsrc/main.rs:2\n");
+    rgrep(dir.path(), &["-l", "fn", "."])
+        .assert()
+        .stdout("./src/lib.rs\n./src/main.rs\n");
+    rgrep(dir.path(), &["-L", "fn", "."])
+        .assert()
+        .stdout("./notes.txt\n");
+    rgrep(
+        dir.path(),
+        &["--sortr=line", "-e", "let", "-e", "fn", "src"],
+    )
+    .assert()
+    .stdout(
+        "src/main.rs:3:     let y = x;\n\
+             src/main.rs:2:     let x = 1;\n\
+             src/lib.rs:1: pub fn f() {}\n\
+             src/main.rs:1: fn main() {\n",
+    );
+    rgrep(dir.path(), &["-s", "-e", "let", "-e", "fn", "src"])
+        .assert()
+        .stdout(contains(
+            "    let x = 1;\nsrc/main.rs:3:     let y = x;\nsrc/main.rs:1: fn",
+        ));
+    rgrep(
+        dir.path(),
+        &["--replace=var $0", "-r", r"\bx\b", "src/main.rs"],
+    )
+    .assert()
+    .stdout("src/main.rs:2:     let var x = 1;\nsrc/main.rs:3:     let y = var x;\n");
+    rgrep(dir.path(), &["-o", "-r", "x*", "src/main.rs"])
+        .assert()
+        .stdout("src/main.rs:2: x\nsrc/main.rs:3: x\n");
+    rgrep(dir.path(), &["-o", "", "notes.txt"])
+        .assert()
+        .stdout("");
+    rgrep(dir.path(), &["--max-count=1", "let", "src"])
+        .assert()
+        .stdout("src/main.rs:2:     let x = 1;\n");
+    rgrep(
+        dir.path(),
+        &["--max-total=1", "-e", "fn", "-e", "let", "src"],
+    )
+    .assert()
+    .stdout("src/lib.rs:1: pub fn f() {}\n");
+}
+
+#[test]
+fn test_quiet() {
+    let dir = tree(FILES);
+
+    rgrep(dir.path(), &["-q", "let", "src"])
+        .assert()
+        .code(0)
+        .stdout("");
+    rgrep(dir.path(), &["-q", "nowhere", "src"])
+        .assert()
+        .code(1)
+        .stdout("");
+    // Errors are told apart from not finding anything, unless a quiet search found a match anyway.
+    rgrep(dir.path(), &["-q", "let", "missing"])
+        .assert()
+        .code(2)
+        .stderr(contains("rgrep: missing"));
+    rgrep(dir.path(), &["-q", "let", "missing", "src/main.rs"])
+        .assert()
+        .code(0);
+}
+
+#[test]
+fn test_json() {
+    let dir = tree(FILES);
+
+    rgrep(dir.path(), &["--json", "y", "src/main.rs"])
+        .assert()
+        .success()
+        .stdout(
+            contains(r#"{"type":"begin","path":"src/main.rs"}"#)
+                .and(contains(
+                    r#"{"type":"match","path":"src/main.rs","line_number":3,"absolute_offset":27,"#,
+                ))
+                .and(contains(
+                    r#"{"type":"end","path":"src/main.rs","matched_lines":1,"matches":1}"#,
+                ))
+                .and(contains(
+                    r#"{"type":"summary","patterns":["y"],"matched_lines":1,"#,
+                )),
+        );
+    // A file given twice is two files, each with its own begin and end.
+    rgrep(dir.path(), &["--json", "y", "src/main.rs", "src/main.rs"])
+        .assert()
+        .success()
+        .stdout(
+            contains(r#""matched_lines":1,"matches":1}"#)
+                .count(2)
+                .and(contains(r#""files_with_matches":2,"#)),
+        );
+}
+
+#[test]
+fn test_stats() {
+    let dir = tree(FILES);
+
+    rgrep(dir.path(), &["--stats", "let", "src"])
+        .assert()
+        .success()
+        .stdout(
+            contains("\n2 files searched\n")
+                .and(contains("\n2 matching lines\n"))
+                .and(contains("seconds total")),
+        );
+}
+
+#[test]
+fn test_in_place() {
+    let dir = tree(FILES);
+
+    rgrep(
+        dir.path(),
+        &["--replace=z", "--in-place", "--dry-run", "y", "src"],
+    )
+    .assert()
+    .success()
+    .stdout(contains("-    let y = x;\n+    let z = x;\n"));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("src/main.rs")).unwrap(),
+        FILES[0].1
+    );
+
+    rgrep(dir.path(), &["--replace=z", "--in-place", "y", "src"])
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(dir.path().join("src/main.rs")).unwrap(),
+        "fn main() {\n    let x = 1;\n    let z = x;\n}\n"
+    );
+}
+
+#[test]
+fn test_files() {
+    let dir = tree(FILES);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"zipped let\n").unwrap();
+    fs::write(dir.path().join("log.gz"), encoder.finish().unwrap()).unwrap();
+    fs::write(dir.path().join("a.out"), b"\x7fELF\x00let\n").unwrap();
+
+    rgrep(dir.path(), &["-g", "*.rs", "-g", "!lib.rs", "fn", "."])
+        .assert()
+        .stdout("./src/main.rs:1: fn main() {\n");
+    rgrep(dir.path(), &["-t", "rust", "-l", "", "."])
+        .assert()
+        .stdout("./src/lib.rs\n./src/main.rs\n");
+    rgrep(dir.path(), &["-T", "rust", "-l", "", "."])
+        .assert()
+        .stdout("./a.out\n./log.gz\n./notes.txt\n");
+    rgrep(dir.path(), &["-t", "cobol", "x", "."])
+        .assert()
+        .code(2)
+        .stderr(contains("cobol"));
+
+    rgrep(dir.path(), &["-z", "zipped", "log.gz"])
+        .assert()
+        .stdout("log.gz:1: zipped let\n");
+    rgrep(dir.path(), &["let", "a.out"])
+        .assert()
+        .stdout("Binary file a.out matches\n");
+    rgrep(dir.path(), &["-a", "-N", "let", "a.out"])
+        .assert()
+        .stdout("a.out: \u{7f}ELF\u{0}let\n");
+    rgrep(dir.path(), &["--skip-binary", "let", "a.out"])
+        .assert()
+        .stdout("");
+    rgrep(dir.path(), &["let", "missing.txt", "src/main.rs"])
+        .assert()
+        .code(2)
+        .stdout(contains("src/main.rs:2:"))
+        .stderr(contains("rgrep: missing.txt: ").and(contains("failed to search 1 file(s)")));
+}
+
+#[test]
+fn test_config() {
+    let dir = tree(FILES);
+    let config = dir.path().join("rgreprc");
+    fs::write(&config, "# Just names.\n-l\n--glob=*.rs\n").unwrap();
+
+    rgrep(dir.path(), &["fn", "."])
+        .env("RGREP_CONFIG", &config)
+        .assert()
+        .stdout("./src/lib.rs\n./src/main.rs\n");
+    rgrep(dir.path(), &["--no-config", "fn", "src/lib.rs"])
+        .env("RGREP_CONFIG", &config)
+        .assert()
+        .stdout("src/lib.rs:1: pub fn f() {}\n");
+    rgrep(dir.path(), &["fn", "."])
+        .env("RGREP_CONFIG", dir.path().join("missing"))
+        .assert()
+        .code(2)
+        .stderr(contains("cannot read the config file"));
+}
+
+#[test]
+fn test_usage() {
+    let dir = tree(FILES);
+
+    rgrep(dir.path(), &["--help"])
+        .assert()
+        .success()
+        .stdout(contains("Usage: rgrep").and(contains("--vimgrep")));
+    rgrep(dir.path(), &[])
+        .assert()
+        .code(2)
+        .stderr(contains("Usage"));
+    rgrep(dir.path(), &["-c", "-l", "x"])
+        .assert()
+        .code(2)
+        .stderr(contains("cannot be used with"));
+    rgrep(dir.path(), &["-j", "0", "x"])
+        .assert()
+        .code(2)
+        .stderr(contains("expected a positive number"));
+    rgrep(dir.path(), &["--generate=bash"])
+        .assert()
+        .success()
+        .stdout(contains("_rgrep()"));
+    rgrep(dir.path(), &["--generate=man"])
+        .assert()
+        .success()
+        .stdout(contains(".TH rgrep"));
+}
+
+#[test]
+fn test_watch() {
+    let dir = tree(&[("app.log", "let old\n")]);
+    let log = dir.path().join("app.log");
+
+    // Only lines appended after the start count. The search ends after the first one, thanks to
+    // '--max-total'.
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        let mut file = OpenOptions::new().append(true).open(log).unwrap();
+        file.write_all(b"nothing\nlet new\n").unwrap();
+    });
+
+    rgrep(dir.path(), &["-W", "--max-total=1", "let", "app.log"])
+        .timeout(Duration::from_secs(20))
+        .assert()
+        .success()
+        .stdout("app.log:3: let new\n");
+    writer.join().unwrap();
+
+    rgrep(dir.path(), &["-W", "let"])
+        .assert()
+        .code(2)
+        .stderr(contains("'--watch' needs files"));
+}